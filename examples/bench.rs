@@ -0,0 +1,245 @@
+// Benchmarks for the hashing, graph generation, solving and verification
+// hot paths.
+//
+//     cargo run --release --example bench -- [filter] [--save NAME] [--compare NAME] [--quick]
+//
+// Results are printed as nanoseconds per iteration. --save writes them to
+// target/cuckoo-bench/NAME.txt, and --compare prints the change against a
+// run saved earlier, so an optimization can be judged on the same machine.
+
+extern crate cuckoo_http;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::hint::black_box;
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, Instant};
+use std::u64;
+
+use cuckoo_http::cuckoo::{self, EdgeGen, KeyingScheme, Params, Proof, Variant};
+use cuckoo_http::lean_miner;
+use cuckoo_http::simple_miner::{self, Arena, CuckooSolve, Layout};
+use cuckoo_http::solver::SolveControl;
+use cuckoo_http::verifier::{BatchVerifier, VerifyJob};
+
+const RESULTS_DIR: &str = "target/cuckoo-bench";
+// Samples taken of each benchmark; the median is reported
+const SAMPLES: usize = 7;
+const QUICK_SAMPLES: usize = 3;
+const EASIPCTS: [i32; 3] = [50, 70, 90];
+
+struct Bench {
+    filter: Option<String>,
+    samples: usize,
+    results: BTreeMap<String, f64>,
+}
+
+impl Bench {
+    fn wants(&self, name: &str) -> bool {
+        self.filter.as_ref().map_or(true, |f| name.contains(f.as_str()))
+    }
+
+    // Times `iters` calls of `f` per sample and records the median time per
+    // call. `f` gets the iteration number so it can vary its input.
+    fn run<F: FnMut(usize)>(&mut self, name: &str, iters: usize, mut f: F) {
+        if !self.wants(name) {
+            return;
+        }
+        let mut samples: Vec<f64> = (0..self.samples)
+            .map(|_| {
+                let started = Instant::now();
+                for i in 0..iters {
+                    f(i);
+                }
+                nanos(started.elapsed()) / iters as f64
+            })
+            .collect();
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median = samples[samples.len() / 2];
+        println!("{:<40} {:>16.1} ns/iter", name, median);
+        self.results.insert(name.to_string(), median);
+    }
+}
+
+fn nanos(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1e9 + d.subsec_nanos() as f64
+}
+
+fn solve_params(header: &str, params: Params, easipct: i32) -> CuckooSolve {
+    CuckooSolve::new(header.as_bytes(), KeyingScheme::Legacy, params, easipct)
+}
+
+// The first of a fixed series of headers whose graph has a proof
+fn find_proof(params: Params, easipct: i32) -> (CuckooSolve, Proof) {
+    for i in 0.. {
+        let cs = solve_params(&format!("bench {}", i), params, easipct);
+        if let Some(proof) = lean_miner::solve(cs.clone()) {
+            return (cs, proof);
+        }
+    }
+    unreachable!()
+}
+
+fn result_path(name: &str) -> PathBuf {
+    PathBuf::from(RESULTS_DIR).join(format!("{}.txt", name))
+}
+
+fn save(name: &str, results: &BTreeMap<String, f64>) {
+    let mut out = String::new();
+    for (bench, ns) in results.iter() {
+        out.push_str(&format!("{} {}\n", bench, ns));
+    }
+    fs::create_dir_all(RESULTS_DIR).unwrap();
+    fs::write(result_path(name), out).unwrap();
+    println!("saved {}", result_path(name).display());
+}
+
+fn compare(name: &str, results: &BTreeMap<String, f64>) {
+    let saved = match fs::read_to_string(result_path(name)) {
+        Ok(saved) => saved,
+        Err(e) => {
+            eprintln!("can't read {}: {}", result_path(name).display(), e);
+            process::exit(1);
+        }
+    };
+    println!("\ncompared with {}:", name);
+    for line in saved.lines() {
+        let mut fields = line.split(' ');
+        let bench = fields.next().unwrap_or("");
+        let before = fields.next().and_then(|ns| ns.parse::<f64>().ok());
+        if let (Some(before), Some(after)) = (before, results.get(bench)) {
+            println!(
+                "{:<40} {:>16.1} -> {:>12.1} ns/iter {:>+8.1}%",
+                bench,
+                before,
+                after,
+                (after - before) / before * 100.0
+            );
+        }
+    }
+}
+
+fn main() {
+    let mut bench = Bench {
+        filter: None,
+        samples: SAMPLES,
+        results: BTreeMap::new(),
+    };
+    let mut save_as = None;
+    let mut compare_to = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--save" => save_as = args.next(),
+            "--compare" => compare_to = args.next(),
+            "--quick" => bench.samples = QUICK_SAMPLES,
+            _ => bench.filter = Some(arg),
+        }
+    }
+
+    let v = cuckoo::hash_header(b"bench");
+    bench.run("siphash24", 1 << 20, |i| {
+        black_box(cuckoo::siphash24(black_box(v), i as u64));
+    });
+    // Eight hashes per iteration
+    bench.run("siphash24_x8", 1 << 17, |i| {
+        let n = 8 * i as u64;
+        black_box(cuckoo::siphash24_x8(black_box(v), [n, n + 1, n + 2, n + 3, n + 4, n + 5, n + 6, n + 7]));
+    });
+    bench.run("sipnode", 1 << 20, |i| {
+        black_box(cuckoo::sipnode(black_box(v), i as i32, 1));
+    });
+    let mut block = [0; cuckoo::EDGE_BLOCK_SIZE];
+    bench.run("sipblock", 1 << 14, |i| {
+        black_box(cuckoo::sipblock(black_box(v), (i * cuckoo::EDGE_BLOCK_SIZE) as i32, &mut block));
+    });
+
+    // Every edge of the graph, in nonce order
+    for &variant in [Variant::Cuckoo, Variant::Cuckaroo].iter() {
+        for &edge_bits in [12, 16, 20].iter() {
+            let params = Params::new(variant, edge_bits);
+            bench.run(&format!("edgegen/{}", params), 1, |_| {
+                let mut edges = EdgeGen::new(params, v);
+                for nonce in 0..params.nedges() {
+                    black_box(edges.edge(nonce));
+                }
+            });
+        }
+    }
+
+    // Each iteration solves a different header; solving is dominated by
+    // the walk over every edge, found or not
+    for &edge_bits in [12, 16, 18].iter() {
+        for &easipct in EASIPCTS.iter() {
+            let params = Params::new(Variant::Cuckoo, edge_bits);
+            let name = format!("solve/simple/{}/e{}", params, easipct);
+            if !bench.wants(&name) {
+                continue;
+            }
+            let solves: Vec<CuckooSolve> = (0..4)
+                .map(|i| solve_params(&format!("bench {}", i), params, easipct))
+                .collect();
+            bench.run(&name, solves.len(), |i| {
+                black_box(simple_miner::solve(solves[i].clone()));
+            });
+        }
+    }
+
+    // Same graphs with the bit-packed tables, reusing one arena
+    for &edge_bits in [16, 18].iter() {
+        let params = Params::new(Variant::Cuckoo, edge_bits);
+        let name = format!("solve/simple-packed/{}/e70", params);
+        let solves: Vec<CuckooSolve> = (0..4)
+            .map(|i| solve_params(&format!("bench {}", i), params, 70))
+            .collect();
+        let mut arena = Arena::new(Layout::Packed);
+        bench.run(&name, solves.len(), |i| {
+            black_box(simple_miner::solve_in(solves[i].clone(), &mut arena, &mut SolveControl::new()));
+        });
+    }
+
+    for &edge_bits in [12, 16, 20].iter() {
+        let params = Params::new(Variant::Cuckoo, edge_bits);
+        let name = format!("verify/{}", params);
+        if !bench.wants(&name) {
+            continue;
+        }
+        let (cs, proof) = find_proof(params, 70);
+        bench.run(&name, 1 << 10, |_| {
+            assert!(black_box(cuckoo::verify_params(
+                cs.params,
+                cs.graph_v,
+                black_box(proof),
+                cs.easiness,
+                cs.target,
+            )));
+        });
+    }
+
+    // A whole batch per iteration, to compare with 256 times verify/
+    let name = "verify/batch256/cuckoo20";
+    if bench.wants(name) {
+        let (cs, proof) = find_proof(Params::new(Variant::Cuckoo, 20), 70);
+        let job = VerifyJob {
+            params: cs.params,
+            keys: cs.graph_v,
+            proof: proof,
+            easiness: cs.easiness,
+            target: cs.target,
+        };
+        let jobs = vec![job; 256];
+        let verifier = BatchVerifier::new();
+        bench.run(name, 16, |_| {
+            black_box(verifier.verify(black_box(&jobs)));
+        });
+    }
+
+    if let Some(name) = save_as {
+        save(&name, &bench.results);
+    }
+    if let Some(name) = compare_to {
+        compare(&name, &bench.results);
+    }
+}
@@ -1,14 +1,18 @@
 extern crate cuckoo_http;
+extern crate env_logger;
 
 use std::io::Read;
 
 use cuckoo_http::cuckoo;
-use cuckoo_http::cuckoo::NNODES;
+use cuckoo_http::difficulty::Target;
+use cuckoo_http::encoding;
 
 fn main() {
+    env_logger::init();
     let mut header = String::new();
     let mut easipct: i32 = 70;
-    let mut difficulty: f64 = 50.0;
+    let mut target = Target::from_expected_attempts(2);
+    let mut params = cuckoo::Params::default();
 
     let mut args = std::env::args();
 
@@ -21,10 +25,12 @@ fn main() {
             Some(arg) => {
                 if arg == "-e" {
                     easipct = args.next().unwrap().parse::<i32>().unwrap();
-                } else if arg == "-d" {
-                    difficulty = (args.next().unwrap().parse::<f64>().unwrap() - 1e-6).abs();
+                } else if arg == "-a" {
+                    target = Target::from_expected_attempts(args.next().unwrap().parse::<u64>().unwrap());
                 } else if arg == "-h" {
                     header = args.next().unwrap();
+                } else if arg == "-p" {
+                    params = cuckoo::Params::parse(&args.next().unwrap()).unwrap();
                 }
             }
             None => break,
@@ -35,20 +41,12 @@ fn main() {
     let mut contents = String::new();
     f.read_to_string(&mut contents)
         .expect("Unable to read the file");
-    contents = contents.trim().to_string();
+    let nonces = encoding::decode_proof(&contents).expect("Expected 42 nonces in hex, base64url or json");
 
-    let mut raw_nonces: Vec<i32> = contents
-        .split(" ")
-        .map(|a| i32::from_str_radix(a, 16).unwrap())
-        .collect();
-    let mut nonces = [0; cuckoo::PROOFSIZE];
-    nonces.copy_from_slice(&mut raw_nonces);
-
-    let easiness: i32 = ((easipct as i64 * NNODES as i64) / 100) as i32;
-    let hash_difficulty: u64 = ((difficulty / 100.0) * std::u64::MAX as f64) as u64;
+    let easiness: i32 = ((easipct as i64 * params.nnodes() as i64) / 100) as i32;
     let v = cuckoo::hash_header(header.as_bytes());
 
-    let result = cuckoo::verify(v, nonces, easiness, hash_difficulty);
+    let result = cuckoo::verify_params(params, v, nonces, easiness, target);
     if result {
         println!("Verified!");
     } else {
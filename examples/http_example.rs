@@ -1,7 +1,56 @@
 extern crate cuckoo_http;
+extern crate env_logger;
 
-use cuckoo_http::http_server;
+use cuckoo_http::access_log::{AccessLog, LogFormat};
+use cuckoo_http::gateway::GatewayBuilder;
 
 fn main() {
-    http_server::server_start("0.0.0.0:8080".to_string());
+    env_logger::init();
+
+    let mut builder = GatewayBuilder::new().inherit_listener();
+    let mut listen = String::from("0.0.0.0:8080");
+    let mut format: Option<LogFormat> = None;
+    let mut log_path: Option<String> = None;
+    let mut admin: Option<String> = None;
+    let mut token: Option<String> = None;
+
+    let mut args = std::env::args();
+    args.next();
+    loop {
+        match args.next() {
+            Some(arg) => {
+                if arg == "-l" {
+                    format = Some(LogFormat::parse(&args.next().unwrap()).unwrap());
+                } else if arg == "-o" {
+                    log_path = args.next();
+                } else if arg == "-a" {
+                    admin = args.next();
+                } else if arg == "-t" {
+                    token = args.next();
+                } else if arg == "-u" {
+                    builder = builder.upstream(&args.next().unwrap());
+                } else if arg == "-p" {
+                    builder = builder.api_path(&args.next().unwrap());
+                } else if arg == "-s" {
+                    builder = builder.state_path(&args.next().unwrap());
+                } else {
+                    listen = arg;
+                }
+            }
+            None => break,
+        }
+    }
+
+    if let Some(format) = format {
+        let log = match log_path {
+            Some(path) => AccessLog::file(format, &path).unwrap(),
+            None => AccessLog::stdout(format),
+        };
+        builder = builder.access_log(log);
+    }
+    if let Some(admin) = admin {
+        builder = builder.admin(&admin, token);
+    }
+
+    builder.listen(&listen).build().unwrap().run().unwrap();
 }
@@ -1,15 +1,22 @@
 extern crate cuckoo_http;
+extern crate env_logger;
 
 use std::fs;
 
 use cuckoo_http::cuckoo;
-use cuckoo_http::cuckoo::NNODES;
-use cuckoo_http::simple_miner::{solve, CuckooSolve};
+use cuckoo_http::difficulty::Target;
+use cuckoo_http::encoding::Encoding;
+use cuckoo_http::lean_miner;
+use cuckoo_http::mean_miner;
+use cuckoo_http::simple_miner::{self, CuckooSolve};
 
 fn main() {
+    env_logger::init();
     let mut header = String::new();
     let mut easipct: i32 = 70;
-    let mut difficulty: f64 = 50.0;
+    let mut target = Target::from_expected_attempts(2);
+    let mut params = cuckoo::Params::default();
+    let mut miner = String::from("simple");
 
     let mut args = std::env::args();
 
@@ -23,18 +30,21 @@ fn main() {
             Some(arg) => {
                 if arg == "-e" {
                     easipct = args.next().unwrap().parse::<i32>().unwrap();
-                } else if arg == "-d" {
-                    difficulty = (args.next().unwrap().parse::<f64>().unwrap() - 1e-6).abs();
+                } else if arg == "-a" {
+                    target = Target::from_expected_attempts(args.next().unwrap().parse::<u64>().unwrap());
                 } else if arg == "-h" {
                     header = args.next().unwrap();
+                } else if arg == "-p" {
+                    params = cuckoo::Params::parse(&args.next().unwrap()).unwrap();
+                } else if arg == "-m" {
+                    miner = args.next().unwrap();
                 }
             }
             None => break,
         }
     }
 
-    let easiness: i32 = ((easipct as i64 * NNODES as i64) / 100) as i32;
-    let hash_difficulty: u64 = ((difficulty / 100.0) * std::u64::MAX as f64) as u64;
+    let easiness: i32 = ((easipct as i64 * params.nnodes() as i64) / 100) as i32;
     let v = cuckoo::hash_header(header.as_bytes());
     /*let v: [u64; 4] = [
         1449310910991872227,
@@ -46,20 +56,19 @@ fn main() {
     let cs = CuckooSolve {
         graph_v: v,
         easiness: easiness,
-        hash_difficulty: hash_difficulty,
-        cuckoo: vec![0; (1 + NNODES) as usize],
+        target: target,
+        params: params,
     };
 
-    let result = solve(cs);
+    let result = match miner.as_str() {
+        "lean" => lean_miner::solve(cs),
+        "mean" => mean_miner::solve(cs),
+        "parallel" => simple_miner::solve_parallel(cs, &simple_miner::ParallelConfig::default()),
+        _ => simple_miner::solve(cs),
+    };
 
     match result {
         None => return,
-        Some(r) => fs::write(
-            filename,
-            r.into_iter()
-                .map(|x| format!("{:x} ", x))
-                .collect::<Vec<_>>()
-                .concat(),
-        ).unwrap(),
+        Some(r) => fs::write(filename, r.to_hex()).unwrap(),
     };
 }
@@ -0,0 +1,351 @@
+use std::fmt::Write as FmtWrite;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cuckoo::Params;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    // Common Log Format, with the gateway's fields appended as key=value
+    Common,
+    // One JSON object per line
+    JsonLines,
+}
+
+impl LogFormat {
+    pub fn parse(s: &str) -> Option<LogFormat> {
+        match s {
+            "clf" | "common" => Some(LogFormat::Common),
+            "json" => Some(LogFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    // Served one of the miner's static files
+    Static,
+    // Answered with a fresh challenge
+    Challenged,
+    Verified,
+    Rejected(&'static str),
+    // On the allow list, so let through without a challenge
+    Allowed,
+    // On the deny list
+    Denied,
+    // Path doesn't need a challenge
+    Exempt,
+    // Let through on a clearance token from an earlier solve
+    Cleared,
+}
+
+impl Outcome {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Outcome::Static => "static",
+            Outcome::Challenged => "challenged",
+            Outcome::Verified => "verified",
+            Outcome::Rejected(_) => "rejected",
+            Outcome::Allowed => "allowed",
+            Outcome::Denied => "denied",
+            Outcome::Exempt => "exempt",
+            Outcome::Cleared => "cleared",
+        }
+    }
+
+    pub fn reason(&self) -> Option<&'static str> {
+        match *self {
+            Outcome::Rejected(reason) => Some(reason),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessEntry {
+    pub time: SystemTime,
+    pub client: String,
+    pub method: String,
+    pub url: String,
+    // From the request line, e.g. "HTTP/1.1"; empty if it had none
+    pub protocol: String,
+    // Status and body length of what we sent back, if anything
+    pub status: Option<u16>,
+    pub bytes: usize,
+    pub challenge: Option<String>,
+    pub params: Option<Params>,
+    pub outcome: Outcome,
+    // Time between issuing the challenge and receiving its solution
+    pub solve_latency: Option<Duration>,
+    pub upstream_status: Option<u16>,
+    pub upstream_bytes: Option<usize>,
+}
+
+impl AccessEntry {
+    pub fn new(client: &str, method: &str, url: &str, outcome: Outcome) -> AccessEntry {
+        AccessEntry {
+            time: SystemTime::now(),
+            client: client.to_string(),
+            method: method.to_string(),
+            url: url.to_string(),
+            protocol: String::new(),
+            status: None,
+            bytes: 0,
+            challenge: None,
+            params: None,
+            outcome,
+            solve_latency: None,
+            upstream_status: None,
+            upstream_bytes: None,
+        }
+    }
+}
+
+pub struct AccessLog {
+    format: LogFormat,
+    sink: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn new(format: LogFormat, sink: Box<dyn Write + Send>) -> AccessLog {
+        AccessLog {
+            format,
+            sink: Mutex::new(sink),
+        }
+    }
+
+    pub fn stdout(format: LogFormat) -> AccessLog {
+        AccessLog::new(format, Box::new(io::stdout()))
+    }
+
+    // Appends to `path`, creating it if needed
+    pub fn file(format: LogFormat, path: &str) -> io::Result<AccessLog> {
+        let f = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog::new(format, Box::new(f)))
+    }
+
+    pub fn format(&self) -> LogFormat {
+        self.format
+    }
+
+    pub fn record(&self, entry: &AccessEntry) {
+        let line = match self.format {
+            LogFormat::Common => format_common(entry),
+            LogFormat::JsonLines => format_json(entry),
+        };
+        let mut sink = self.sink.lock().unwrap();
+        if let Err(e) = sink.write_all(line.as_bytes()).and_then(|_| sink.flush()) {
+            warn!("access log write failed error={}", e);
+        }
+    }
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_millis() as u64
+}
+
+// Days since the epoch to (year, month, day), valid for any date after 1970
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+// (year, month, day, hour, minute, second) in UTC
+fn utc(time: SystemTime) -> (u64, u64, u64, u64, u64, u64) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (y, m, d) = civil_from_days(secs / 86400);
+    let s = secs % 86400;
+    (y, m, d, s / 3600, s / 60 % 60, s % 60)
+}
+
+// Client-supplied text for a CLF line. Fields there are split on spaces
+// and quotes, one request per line, so bytes outside printable ASCII, quotes
+// and backslashes are percent-encoded.
+fn clf_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'"' | b'\\' => write!(out, "%{:02X}", b).unwrap(),
+            b'!'..=b'~' => out.push(b as char),
+            _ => write!(out, "%{:02X}", b).unwrap(),
+        }
+    }
+    out
+}
+
+fn or_dash<T: ToString>(v: Option<T>) -> String {
+    match v {
+        Some(v) => v.to_string(),
+        None => String::from("-"),
+    }
+}
+
+pub fn format_common(e: &AccessEntry) -> String {
+    let (y, mo, d, h, mi, s) = utc(e.time);
+    let mut request = format!("{} {}", clf_escape(&e.method), clf_escape(&e.url));
+    if !e.protocol.is_empty() {
+        request.push(' ');
+        request.push_str(&clf_escape(&e.protocol));
+    }
+    format!(
+        "{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{}\" {} {} challenge={} params={} outcome={} reason={} solve_ms={} upstream_status={} upstream_bytes={}\n",
+        clf_escape(&e.client),
+        d,
+        MONTHS[(mo - 1) as usize],
+        y,
+        h,
+        mi,
+        s,
+        request,
+        or_dash(e.status),
+        e.bytes,
+        or_dash(e.challenge.as_ref().map(|c| clf_escape(c))),
+        or_dash(e.params),
+        e.outcome.name(),
+        or_dash(e.outcome.reason()),
+        or_dash(e.solve_latency.map(millis)),
+        or_dash(e.upstream_status),
+        or_dash(e.upstream_bytes),
+    )
+}
+
+pub fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                write!(out, "\\u{:04x}", c as u32).unwrap();
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn json_field<T: ToString>(out: &mut String, name: &str, v: Option<T>, quoted: bool) {
+    write!(out, ",\"{}\":", name).unwrap();
+    match v {
+        Some(v) if quoted => json_string(out, &v.to_string()),
+        Some(v) => out.push_str(&v.to_string()),
+        None => out.push_str("null"),
+    }
+}
+
+pub fn format_json(e: &AccessEntry) -> String {
+    let (y, mo, d, h, mi, s) = utc(e.time);
+    let mut out = String::new();
+    write!(
+        out,
+        "{{\"time\":\"{}-{:02}-{:02}T{:02}:{:02}:{:02}Z\"",
+        y, mo, d, h, mi, s
+    ).unwrap();
+    json_field(&mut out, "client", Some(&e.client), true);
+    json_field(&mut out, "method", Some(&e.method), true);
+    json_field(&mut out, "url", Some(&e.url), true);
+    json_field(&mut out, "protocol", Some(&e.protocol).filter(|p| !p.is_empty()), true);
+    json_field(&mut out, "status", e.status, false);
+    json_field(&mut out, "bytes", Some(e.bytes), false);
+    json_field(&mut out, "challenge", e.challenge.as_ref(), true);
+    json_field(&mut out, "params", e.params, true);
+    json_field(&mut out, "outcome", Some(e.outcome.name()), true);
+    json_field(&mut out, "reason", e.outcome.reason(), true);
+    json_field(&mut out, "solve_ms", e.solve_latency.map(millis), false);
+    json_field(&mut out, "upstream_status", e.upstream_status, false);
+    json_field(&mut out, "upstream_bytes", e.upstream_bytes, false);
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use access_log::{format_common, format_json, AccessEntry, Outcome};
+    use cuckoo::Params;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn entry() -> AccessEntry {
+        let mut e = AccessEntry::new("10.0.0.1", "GET", "/a\"b", Outcome::Rejected("invalid_proof"));
+        // 2000-10-10T13:55:36Z
+        e.time = UNIX_EPOCH + Duration::new(971186136, 0);
+        e.protocol = String::from("HTTP/1.1");
+        e.challenge = Some(String::from("abc"));
+        e.params = Some(Params::default());
+        e.solve_latency = Some(Duration::new(1, 500_000_000));
+        e
+    }
+
+    #[test]
+    fn formats_common_log() {
+        assert_eq!(
+            format_common(&entry()),
+            "10.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /a%22b HTTP/1.1\" - 0 challenge=abc \
+             params=cuckoo22 outcome=rejected reason=invalid_proof solve_ms=1500 \
+             upstream_status=- upstream_bytes=-\n"
+        );
+    }
+
+    #[test]
+    fn formats_json_lines() {
+        let mut e = entry();
+        e.outcome = Outcome::Verified;
+        e.upstream_status = Some(200);
+        e.upstream_bytes = Some(12);
+        assert_eq!(
+            format_json(&e),
+            "{\"time\":\"2000-10-10T13:55:36Z\",\"client\":\"10.0.0.1\",\"method\":\"GET\",\
+             \"url\":\"/a\\\"b\",\"protocol\":\"HTTP/1.1\",\"status\":null,\"bytes\":0,\"challenge\":\"abc\",\
+             \"params\":\"cuckoo22\",\"outcome\":\"verified\",\"reason\":null,\
+             \"solve_ms\":1500,\"upstream_status\":200,\"upstream_bytes\":12}\n"
+        );
+    }
+
+    #[test]
+    fn escapes_client_fields_in_common_log() {
+        // A challenge header can hold anything up to the \r, so it could
+        // forge a second line or extra key=value fields
+        let mut e = AccessEntry::new(
+            "10.0.0.1\"",
+            "GET\t",
+            "/\u{e9}",
+            Outcome::Rejected("unknown_challenge"),
+        );
+        e.time = UNIX_EPOCH;
+        e.protocol = String::from("HTTP/1.1 x");
+        e.challenge = Some(String::from(
+            "x outcome=verified\n1.2.3.4 - - [01/Jan/1970:00:00:00 +0000] \"GET /\" 200 0",
+        ));
+        let line = format_common(&e);
+        assert_eq!(
+            line,
+            "10.0.0.1%22 - - [01/Jan/1970:00:00:00 +0000] \"GET%09 /%C3%A9 HTTP/1.1%20x\" - 0 \
+             challenge=x%20outcome=verified%0A1.2.3.4%20-%20-%20[01/Jan/1970:00:00:00%20+0000]\
+             %20%22GET%20/%22%20200%200 params=- outcome=rejected reason=unknown_challenge \
+             solve_ms=- upstream_status=- upstream_bytes=-\n"
+        );
+        assert_eq!(line.matches('\n').count(), 1);
+        assert_eq!(line.matches(" outcome=").count(), 1);
+    }
+}
@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use access_log::json_string;
+use cuckoo::Params;
+use difficulty::Target;
+use http_server::{GatewayState, PuzzlePolicy, ServerOptions};
+
+const MAX_REQUEST: usize = 8192;
+
+struct AdminRequest {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    token: Option<String>,
+}
+
+struct AdminResponse {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl AdminResponse {
+    fn json(status: &'static str, body: String) -> AdminResponse {
+        AdminResponse {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn ok(body: String) -> AdminResponse {
+        AdminResponse::json("200 OK", body)
+    }
+
+    fn error(status: &'static str, message: &str) -> AdminResponse {
+        let mut body = String::from("{\"error\":");
+        json_string(&mut body, message);
+        body.push('}');
+        AdminResponse::json(status, body)
+    }
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                    (Some(h), Some(l)) => {
+                        out.push(h << 4 | l);
+                        i += 2;
+                    }
+                    _ => out.push(b'%'),
+                }
+            }
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|kv| !kv.is_empty())
+        .map(|kv| match kv.find('=') {
+            Some(i) => (percent_decode(&kv[..i]), percent_decode(&kv[i + 1..])),
+            None => (percent_decode(kv), String::new()),
+        })
+        .collect()
+}
+
+fn parse_request(head: &str) -> Option<AdminRequest> {
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?;
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], parse_query(&target[i + 1..])),
+        None => (target, HashMap::new()),
+    };
+
+    let mut token = None;
+    for line in lines {
+        if let Some(i) = line.find(':') {
+            if line[..i].eq_ignore_ascii_case("authorization") {
+                let value = line[i + 1..].trim();
+                if value.len() > 7 && value[..7].eq_ignore_ascii_case("bearer ") {
+                    token = Some(value[7..].trim().to_string());
+                }
+            }
+        }
+    }
+
+    Some(AdminRequest {
+        method,
+        path: path.to_string(),
+        query,
+        token,
+    })
+}
+
+// Reads up to the end of the headers. Admin requests carry everything in
+// the query string, so any body is ignored.
+fn read_head(stream: &mut TcpStream) -> Option<String> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST {
+            return None;
+        }
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => head.extend_from_slice(&buf[..n]),
+        }
+    }
+    String::from_utf8(head).ok()
+}
+
+fn tokens_match(given: &str, expected: &str) -> bool {
+    let (a, b) = (given.as_bytes(), expected.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn policy_json(out: &mut String, p: &PuzzlePolicy) {
+    write!(
+        out,
+        "{{\"easipct\":{},\"target\":\"{}\",\"expected_attempts\":{},\"params\":\"{}\",\"keying\":\"{}\"}}",
+        p.easipct,
+        p.target,
+        p.target.expected_attempts(),
+        p.params,
+        p.keying.name()
+    ).unwrap();
+}
+
+fn policies_json(state: &GatewayState) -> String {
+    let mut out = String::from("{\"policy\":");
+    policy_json(&mut out, &state.policy());
+    out.push_str(",\"attack_policy\":");
+    policy_json(&mut out, &state.attack_policy());
+    write!(out, ",\"under_attack\":{}}}", state.under_attack()).unwrap();
+    out
+}
+
+// Applies whichever of easipct, target and params the query sets. The
+// target can also be given as attempts, the proofs a client should expect to
+// find per accepted one, or as the old difficulty percentage.
+fn updated_policy(mut p: PuzzlePolicy, query: &HashMap<String, String>) -> Result<PuzzlePolicy, String> {
+    if let Some(v) = query.get("easipct") {
+        p.easipct = match v.parse::<i32>() {
+            Ok(e) if e > 0 && e <= 100 => e,
+            _ => return Err(format!("bad easipct {}", v)),
+        };
+    }
+    if let Some(v) = query.get("target") {
+        p.target = Target::parse(v).ok_or_else(|| format!("bad target {}", v))?;
+    } else if let Some(v) = query.get("attempts") {
+        p.target = match v.parse::<u64>() {
+            Ok(a) if a > 0 => Target::from_expected_attempts(a),
+            _ => return Err(format!("bad attempts {}", v)),
+        };
+    } else if let Some(v) = query.get("difficulty") {
+        p.target = match v.parse::<f64>() {
+            Ok(d) if d > 0.0 && d <= 100.0 => Target::from_percent(d),
+            _ => return Err(format!("bad difficulty {}", v)),
+        };
+    }
+    if let Some(v) = query.get("params") {
+        p.params = Params::parse(v).ok_or_else(|| format!("bad params {}", v))?;
+    }
+    Ok(p)
+}
+
+fn ip_list_json(name: &str, ips: Vec<IpAddr>) -> String {
+    let mut out = format!("{{\"{}\":[", name);
+    for (i, ip) in ips.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        json_string(&mut out, &ip.to_string());
+    }
+    out.push_str("]}");
+    out
+}
+
+fn query_ip(req: &AdminRequest) -> Result<IpAddr, AdminResponse> {
+    match req.query.get("ip").map(|ip| ip.parse::<IpAddr>()) {
+        Some(Ok(ip)) => Ok(ip),
+        _ => Err(AdminResponse::error("400 Bad Request", "expected ?ip=<address>")),
+    }
+}
+
+fn route(req: &AdminRequest, state: &GatewayState, options: &ServerOptions) -> AdminResponse {
+    match (req.method.as_str(), req.path.as_str()) {
+        ("GET", "/metrics") => AdminResponse {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4",
+            body: options.metrics.render(),
+        },
+        ("GET", "/policy") => AdminResponse::ok(policies_json(state)),
+        ("PUT", "/policy") => match updated_policy(state.policy(), &req.query) {
+            Ok(p) => {
+                info!("admin policy updated easipct={} target={} params={}", p.easipct, p.target, p.params);
+                state.set_policy(p);
+                AdminResponse::ok(policies_json(state))
+            }
+            Err(e) => AdminResponse::error("400 Bad Request", &e),
+        },
+        ("PUT", "/attack-policy") => match updated_policy(state.attack_policy(), &req.query) {
+            Ok(p) => {
+                info!("admin attack policy updated easipct={} target={} params={}", p.easipct, p.target, p.params);
+                state.set_attack_policy(p);
+                AdminResponse::ok(policies_json(state))
+            }
+            Err(e) => AdminResponse::error("400 Bad Request", &e),
+        },
+        ("PUT", "/under-attack") => match req.query.get("enabled").map(|v| v.as_str()) {
+            Some("true") | Some("false") => {
+                let enabled = req.query["enabled"] == "true";
+                warn!("admin under_attack={}", enabled);
+                state.set_under_attack(enabled);
+                AdminResponse::ok(policies_json(state))
+            }
+            _ => AdminResponse::error("400 Bad Request", "expected ?enabled=true|false"),
+        },
+        ("GET", "/challenges") => {
+            let challenges = state.challenges();
+            let mut out = format!("{{\"pending\":{},\"challenges\":[", challenges.len());
+            for (i, c) in challenges.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str("{\"id\":");
+                json_string(&mut out, &c.id);
+                let age_ms = c.age.as_secs() * 1000 + c.age.subsec_millis() as u64;
+                write!(out, ",\"params\":\"{}\",\"age_ms\":{}}}", c.params, age_ms).unwrap();
+            }
+            out.push_str("]}");
+            AdminResponse::ok(out)
+        }
+        ("DELETE", "/challenges") => {
+            let purged = state.purge_challenges(req.query.get("id").map(|id| id.as_str()));
+            options
+                .metrics
+                .pending_challenges
+                .set(state.pending_challenges() as i64);
+            info!("admin challenges purged count={}", purged);
+            AdminResponse::ok(format!("{{\"purged\":{}}}", purged))
+        }
+        ("GET", "/allow") => AdminResponse::ok(ip_list_json("allow", state.allowed())),
+        ("PUT", "/allow") => match query_ip(req) {
+            Ok(ip) => {
+                state.allow(ip);
+                AdminResponse::ok(ip_list_json("allow", state.allowed()))
+            }
+            Err(resp) => resp,
+        },
+        ("DELETE", "/allow") => match query_ip(req) {
+            Ok(ip) => {
+                state.remove_allow(&ip);
+                AdminResponse::ok(ip_list_json("allow", state.allowed()))
+            }
+            Err(resp) => resp,
+        },
+        ("GET", "/deny") => AdminResponse::ok(ip_list_json("deny", state.denied())),
+        ("PUT", "/deny") => match query_ip(req) {
+            Ok(ip) => {
+                state.deny(ip);
+                AdminResponse::ok(ip_list_json("deny", state.denied()))
+            }
+            Err(resp) => resp,
+        },
+        ("DELETE", "/deny") => match query_ip(req) {
+            Ok(ip) => {
+                state.remove_deny(&ip);
+                AdminResponse::ok(ip_list_json("deny", state.denied()))
+            }
+            Err(resp) => resp,
+        },
+        // There is no config file behind the policy, which only changes
+        // through /policy and /attack-policy, so this rereads the assets
+        ("POST", "/reload-assets") => match state.reload_assets() {
+            Ok(()) => {
+                info!("admin assets reloaded");
+                AdminResponse::ok(String::from("{\"reloaded\":true}"))
+            }
+            Err(e) => AdminResponse::error("500 Internal Server Error", &e.to_string()),
+        },
+        (_, "/metrics") | (_, "/policy") | (_, "/attack-policy") | (_, "/under-attack")
+        | (_, "/challenges") | (_, "/allow") | (_, "/deny") | (_, "/reload-assets") => {
+            AdminResponse::error("405 Method Not Allowed", "method not allowed")
+        }
+        _ => AdminResponse::error("404 Not Found", "not found"),
+    }
+}
+
+fn handle_admin(mut stream: TcpStream, state: &GatewayState, options: &ServerOptions, token: &str) {
+    stream.set_read_timeout(Some(Duration::new(5, 0))).ok();
+    let resp = match read_head(&mut stream).as_ref().and_then(|h| parse_request(h)) {
+        None => AdminResponse::error("400 Bad Request", "malformed request"),
+        Some(req) => {
+            if req.token.as_ref().is_some_and(|t| tokens_match(t, token)) {
+                route(&req, state, options)
+            } else {
+                warn!("admin unauthorized method={} path={}", req.method, req.path);
+                AdminResponse::error("401 Unauthorized", "unauthorized")
+            }
+        }
+    };
+    let out = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache, private\r\nConnection: close\r\n\r\n{}",
+        resp.status,
+        resp.content_type,
+        resp.body.len(),
+        resp.body
+    );
+    if let Err(e) = stream.write_all(out.as_bytes()) {
+        debug!("admin write failed error={}", e);
+    }
+}
+
+// Serves the admin API on `listener` until `stopping` is set. Every
+// request needs `Authorization: Bearer <token>`. Each connection gets its
+// own thread, so a slow one can't hold up the rest.
+pub fn serve(
+    listener: TcpListener,
+    state: Arc<GatewayState>,
+    options: ServerOptions,
+    token: String,
+    stopping: Arc<AtomicBool>,
+) {
+    let options = Arc::new(options);
+    let token = Arc::new(token);
+    for stream in listener.incoming() {
+        if stopping.load(Ordering::SeqCst) {
+            break;
+        }
+        match stream {
+            Ok(stream) => {
+                let (state, options, token) = (state.clone(), options.clone(), token.clone());
+                thread::spawn(move || handle_admin(stream, &state, &options, &token));
+            }
+            Err(e) => warn!("admin accept failed error={}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use admin::{percent_decode, serve};
+    use difficulty::Target;
+    use http_server::{GatewayState, ServerOptions};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn start() -> (SocketAddr, Arc<GatewayState>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(GatewayState::new());
+        let served = state.clone();
+        thread::spawn(move || serve(
+                listener,
+                served,
+                ServerOptions::default(),
+                "secret".to_string(),
+                Arc::new(AtomicBool::new(false)),
+            ));
+        (addr, state)
+    }
+
+    fn request(addr: SocketAddr, method: &str, target: &str, token: &str) -> String {
+        let mut s = TcpStream::connect(addr).unwrap();
+        write!(
+            s,
+            "{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+            method, target, token
+        ).unwrap();
+        let mut response = String::new();
+        s.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn decodes_query_values() {
+        assert_eq!(percent_decode("a%20b+c%2"), "a b c%2");
+        assert_eq!(percent_decode("%3a%3A"), "::");
+    }
+
+    #[test]
+    fn requires_token() {
+        let (addr, _) = start();
+        // Left open without a request, which mustn't hold up the others
+        let _idle = TcpStream::connect(addr).unwrap();
+        let started = Instant::now();
+        assert!(request(addr, "GET", "/policy", "wrong").starts_with("HTTP/1.1 401"));
+        assert!(request(addr, "GET", "/policy", "secret").starts_with("HTTP/1.1 200"));
+        assert!(request(addr, "GET", "/nope", "secret").starts_with("HTTP/1.1 404"));
+        assert!(request(addr, "POST", "/policy", "secret").starts_with("HTTP/1.1 405"));
+        assert!(request(addr, "GET", "/reload-assets", "secret").starts_with("HTTP/1.1 405"));
+        assert!(started.elapsed() < Duration::new(2, 0));
+    }
+
+    #[test]
+    fn updates_live_state() {
+        let (addr, state) = start();
+
+        let r = request(addr, "PUT", "/policy?easipct=60&params=cuckatoo16", "secret");
+        assert!(r.starts_with("HTTP/1.1 200"));
+        assert_eq!(state.policy().easipct, 60);
+        assert_eq!(state.policy().params.to_string(), "cuckatoo16");
+        assert!(request(addr, "PUT", "/policy?easipct=0", "secret").starts_with("HTTP/1.1 400"));
+        assert_eq!(state.policy().easipct, 60);
+
+        let r = request(addr, "PUT", "/attack-policy?attempts=4", "secret");
+        assert!(r.contains("\"target\":\"4611686018427387903\",\"expected_attempts\":4,"));
+        request(addr, "PUT", "/policy?difficulty=50", "secret");
+        assert_eq!(state.policy().target, Target::from_expected_attempts(2));
+        assert!(request(addr, "PUT", "/policy?target=0.5", "secret").starts_with("HTTP/1.1 400"));
+
+        request(addr, "PUT", "/under-attack?enabled=true", "secret");
+        assert!(state.under_attack());
+        assert_eq!(state.current_policy(), state.attack_policy());
+
+        request(addr, "PUT", "/deny?ip=10.0.0.1", "secret");
+        let r = request(addr, "PUT", "/deny?ip=%3A%3A1", "secret");
+        assert!(r.ends_with("{\"deny\":[\"10.0.0.1\",\"::1\"]}"));
+        request(addr, "DELETE", "/deny?ip=10.0.0.1", "secret");
+        assert_eq!(state.denied().len(), 1);
+
+        let r = request(addr, "DELETE", "/challenges", "secret");
+        assert!(r.ends_with("{\"purged\":0}"));
+
+        let r = request(addr, "GET", "/metrics", "secret");
+        assert!(r.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(r.contains("\ncuckoo_challenges_issued_total 0\n"));
+    }
+}
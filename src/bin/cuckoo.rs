@@ -0,0 +1,455 @@
+extern crate cuckoo_http;
+extern crate log;
+
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::process;
+use std::time::{Duration, Instant};
+
+use cuckoo_http::cuckoo::{self, KeyingScheme, Params, Proof, Variant};
+use cuckoo_http::difficulty::Target;
+use cuckoo_http::encoding::{decode_proof, Challenge, Encoding};
+use cuckoo_http::http_server::PuzzlePolicy;
+use cuckoo_http::lean_miner;
+use cuckoo_http::mean_miner::{self, MeanConfig};
+use cuckoo_http::simple_miner::{self, ParallelConfig};
+use cuckoo_http::solver::{SolveControl, SolveStatus};
+
+// Logs to stderr at the level named by RUST_LOG, warn if unset
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{} {}: {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+fn init_logging() {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|l| l.parse::<log::LevelFilter>().ok())
+        .unwrap_or(log::LevelFilter::Warn);
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+// Exit codes
+const EXIT_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_IO: i32 = 3;
+
+const USAGE: &str = "\
+usage: cuckoo <command> [options]
+
+commands:
+  mine           find a proof for the header and print it
+  verify         read a proof from stdin (or --input) and check it
+  bench          time the miner and verifier over --runs headers
+  inspect-graph  print statistics about the header's graph
+
+puzzle options, shared by every command:
+  -H, --header <text>       header to key the graph with [default: empty]
+  -e, --easiness <pct>      edges as a percentage of nodes [default: 70]
+  -a, --attempts <n>        proofs expected per accepted one [default: 1]
+      --target <n>          largest accepted top 64 bits of the proof hash,
+                            instead of --attempts
+  -b, --edge-bits <n>       log2 of the number of edges, 7 to 29 [default: 22]
+  -v, --variant <name>      cuckoo, cuckatoo or cuckaroo [default: cuckoo]
+  -k, --keying <name>       legacy or blake2b256 [default: legacy]
+
+other options:
+  -m, --miner <name>        simple, lean, mean or parallel [default: simple]
+  -t, --timeout <secs>      give up on a solve after this long
+  -T, --threads <n>         threads for the parallel miner
+  -f, --format <name>       proof format, hex, json or base64 [default: hex]
+  -i, --input <path>        read the proof to verify from a file
+  -n, --runs <n>            headers to try in bench [default: 5]
+  -c, --cycles              also count cycles by length in inspect-graph
+  -h, --help                print this help
+
+Proofs are 42 nonces. In hex they are separated by spaces, as sent in the
+X-Cuckoo-Solution header. In json they are {\"nonces\": [...]}, along with
+the challenge they solve. base64 is base64url of the nonces as 4 byte
+big-endian integers. verify accepts any of them.
+
+exit status: 0 on success, 1 if no proof was found or the proof is invalid,
+2 on bad usage, 3 if input or output failed.";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Hex,
+    Json,
+    Base64,
+}
+
+struct Options {
+    challenge: Challenge,
+    miner: String,
+    timeout: Option<Duration>,
+    threads: Option<usize>,
+    format: Format,
+    input: Option<String>,
+    runs: usize,
+    cycles: bool,
+}
+
+enum Failure {
+    // Nothing went wrong, the answer is just no
+    Failed(String),
+    Usage(String),
+    Io(String),
+}
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Failure {
+        Failure::Io(e.to_string())
+    }
+}
+
+fn value<T, F: Fn(&str) -> Option<T>>(
+    args: &mut dyn Iterator<Item = String>,
+    flag: &str,
+    parse: F,
+) -> Result<T, Failure> {
+    let arg = args
+        .next()
+        .ok_or_else(|| Failure::Usage(format!("{} needs a value", flag)))?;
+    parse(&arg).ok_or_else(|| Failure::Usage(format!("bad value for {}: {}", flag, arg)))
+}
+
+fn parse_options(args: &mut dyn Iterator<Item = String>) -> Result<Options, Failure> {
+    let policy = PuzzlePolicy::default();
+    let mut header = String::new();
+    let mut easipct = policy.easipct;
+    let mut target = policy.target;
+    let mut edge_bits = policy.params.edge_bits;
+    let mut variant = policy.params.variant;
+    let mut options = Options {
+        challenge: Challenge {
+            header: String::new(),
+            easipct: easipct,
+            target: target,
+            params: policy.params,
+            keying: policy.keying,
+        },
+        miner: String::from("simple"),
+        timeout: None,
+        threads: None,
+        format: Format::Hex,
+        input: None,
+        runs: 5,
+        cycles: false,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-H" | "--header" => header = value(args, &arg, |s| Some(s.to_string()))?,
+            "-e" | "--easiness" => {
+                easipct = value(args, &arg, |s| s.parse::<i32>().ok().filter(|e| *e > 0 && *e <= 100))?
+            }
+            "-a" | "--attempts" => {
+                target = value(args, &arg, |s| {
+                    s.parse::<u64>().ok().filter(|a| *a > 0).map(Target::from_expected_attempts)
+                })?
+            }
+            "--target" => target = value(args, &arg, Target::parse)?,
+            "-b" | "--edge-bits" => {
+                edge_bits = value(args, &arg, |s| {
+                    s.parse::<u32>()
+                        .ok()
+                        .filter(|b| *b > cuckoo::EDGE_BLOCK_BITS && *b <= cuckoo::MAX_EDGEBITS)
+                })?
+            }
+            "-v" | "--variant" => {
+                variant = value(args, &arg, |s| match s {
+                    "cuckoo" => Some(Variant::Cuckoo),
+                    "cuckatoo" => Some(Variant::Cuckatoo),
+                    "cuckaroo" => Some(Variant::Cuckaroo),
+                    _ => None,
+                })?
+            }
+            "-k" | "--keying" => options.challenge.keying = value(args, &arg, KeyingScheme::parse)?,
+            "-m" | "--miner" => {
+                options.miner = value(args, &arg, |s| match s {
+                    "simple" | "lean" | "mean" | "parallel" => Some(s.to_string()),
+                    _ => None,
+                })?
+            }
+            "-t" | "--timeout" => {
+                options.timeout = Some(value(args, &arg, |s| s.parse::<u64>().ok().map(|t| Duration::new(t, 0)))?)
+            }
+            "-T" | "--threads" => options.threads = Some(value(args, &arg, |s| s.parse::<usize>().ok())?),
+            "-f" | "--format" => {
+                options.format = value(args, &arg, |s| match s {
+                    "hex" => Some(Format::Hex),
+                    "json" => Some(Format::Json),
+                    "base64" => Some(Format::Base64),
+                    _ => None,
+                })?
+            }
+            "-i" | "--input" => options.input = Some(value(args, &arg, |s| Some(s.to_string()))?),
+            "-n" | "--runs" => options.runs = value(args, &arg, |s| s.parse::<usize>().ok())?,
+            "-c" | "--cycles" => options.cycles = true,
+            "-h" | "--help" => {
+                let _ = writeln!(io::stdout(), "{}", USAGE);
+                process::exit(0);
+            }
+            _ => return Err(Failure::Usage(format!("unknown option: {}", arg))),
+        }
+    }
+
+    options.challenge.header = header;
+    options.challenge.easipct = easipct;
+    options.challenge.target = target;
+    options.challenge.params = Params::new(variant, edge_bits);
+    Ok(options)
+}
+
+fn solve(options: &Options, challenge: &Challenge) -> SolveStatus {
+    let mut control = SolveControl::new();
+    if let Some(timeout) = options.timeout {
+        control = control.with_timeout(timeout);
+    }
+    let cs = challenge.solve_params();
+    match options.miner.as_str() {
+        "lean" => lean_miner::solve_with(cs, lean_miner::NTRIMS, &mut control),
+        "mean" => mean_miner::solve_with(cs, &MeanConfig::default(), &mut control),
+        "parallel" => {
+            let mut config = ParallelConfig::default();
+            if let Some(threads) = options.threads {
+                config.threads = threads;
+            }
+            simple_miner::solve_parallel_with(cs, &config, &mut control)
+        }
+        _ => simple_miner::solve_with(cs, &mut control),
+    }
+}
+
+// The challenge's fields and the nonces, in one object
+fn format_json(challenge: &Challenge, proof: &Proof) -> String {
+    let json = challenge.to_json();
+    let nonces = proof.to_json();
+    format!("{},{}", &json[..json.len() - 1], &nonces[1..])
+}
+
+fn mine(options: &Options) -> Result<(), Failure> {
+    match solve(options, &options.challenge) {
+        SolveStatus::Solved(proof) => {
+            let out = match options.format {
+                Format::Hex => proof.to_hex(),
+                Format::Json => format_json(&options.challenge, &proof),
+                Format::Base64 => proof.to_base64url(),
+            };
+            writeln!(io::stdout(), "{}", out)?;
+            Ok(())
+        }
+        SolveStatus::Exhausted => Err(Failure::Failed(String::from("no proof in this graph"))),
+        SolveStatus::TimedOut => Err(Failure::Failed(String::from("timed out"))),
+        SolveStatus::Cancelled => Err(Failure::Failed(String::from("cancelled"))),
+    }
+}
+
+fn verify_command(options: &Options) -> Result<(), Failure> {
+    let input = match options.input {
+        Some(ref path) => fs::read_to_string(path)?,
+        None => {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            input
+        }
+    };
+    // Something that isn't a proof at all is still just an invalid proof
+    let proof = decode_proof(&input).ok_or_else(|| {
+        Failure::Failed(format!("expected {} nonces in hex, base64 or json", cuckoo::PROOFSIZE))
+    })?;
+    if options.challenge.verify(&proof) {
+        writeln!(io::stdout(), "valid")?;
+        Ok(())
+    } else {
+        Err(Failure::Failed(String::from("invalid")))
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1e3 + d.subsec_nanos() as f64 / 1e6
+}
+
+fn bench(options: &Options) -> Result<(), Failure> {
+    let mut out = io::stdout();
+    let base = if options.challenge.header.is_empty() {
+        String::from("bench")
+    } else {
+        options.challenge.header.clone()
+    };
+    let mut solved = 0;
+    let mut solve_total = Duration::new(0, 0);
+    let mut verify_total = Duration::new(0, 0);
+    for run in 0..options.runs {
+        let challenge = Challenge {
+            header: format!("{} {}", base, run),
+            ..options.challenge.clone()
+        };
+        let started = Instant::now();
+        let status = solve(options, &challenge);
+        let solve_time = started.elapsed();
+        solve_total += solve_time;
+        let verify_time = match status {
+            SolveStatus::Solved(proof) => {
+                let started = Instant::now();
+                let valid = challenge.verify(&proof);
+                let verify_time = started.elapsed();
+                verify_total += verify_time;
+                if !valid {
+                    return Err(Failure::Failed(format!("miner produced an invalid proof for {:?}", challenge.header)));
+                }
+                solved += 1;
+                Some(verify_time)
+            }
+            _ => None,
+        };
+        writeln!(
+            out,
+            "run={} header={:?} solved={} solve_ms={:.1} verify_ms={}",
+            run,
+            challenge.header,
+            verify_time.is_some(),
+            millis(solve_time),
+            verify_time.map_or(String::from("-"), |t| format!("{:.3}", millis(t)))
+        )?;
+    }
+    // Runs solve against the target, so misses are already in solve_total
+    writeln!(
+        out,
+        "miner={} params={} easiness={} target={} runs={} solved={} mean_solve_ms={:.1} mean_verify_ms={} \
+         solve_ms_per_proof={}",
+        options.miner,
+        options.challenge.params,
+        options.challenge.easipct,
+        options.challenge.target,
+        options.runs,
+        solved,
+        if options.runs > 0 { millis(solve_total) / options.runs as f64 } else { 0.0 },
+        if solved > 0 { format!("{:.3}", millis(verify_total) / solved as f64) } else { String::from("-") },
+        if solved > 0 { format!("{:.1}", millis(solve_total) / solved as f64) } else { String::from("-") }
+    )?;
+    Ok(())
+}
+
+fn degree_histogram(degrees: &[u32]) -> String {
+    let mut counts = [0usize; 4];
+    let mut max = 0;
+    for d in degrees {
+        counts[(*d as usize).min(3)] += 1;
+        max = max.max(*d);
+    }
+    format!("0:{} 1:{} 2:{} 3+:{} max:{}", counts[0], counts[1], counts[2], counts[3], max)
+}
+
+fn inspect_graph(options: &Options) -> Result<(), Failure> {
+    let mut out = io::stdout();
+    let cs = options.challenge.solve_params();
+    let params = cs.params;
+    let limit = params.nonce_limit(cs.easiness);
+
+    let mut u_degrees = vec![0u32; params.nedges() as usize];
+    let mut v_degrees = vec![0u32; params.nedges() as usize];
+    let mut edges = cuckoo::EdgeGen::new(params, cs.graph_v);
+    for nonce in 0..limit {
+        let e = edges.edge(nonce);
+        u_degrees[e.u as usize] += 1;
+        v_degrees[e.v as usize] += 1;
+    }
+    writeln!(out, "params {}", params)?;
+    writeln!(out, "keying {}", options.challenge.keying.name())?;
+    writeln!(out, "nodes {} per side", params.nedges())?;
+    writeln!(out, "edges {}", limit)?;
+    writeln!(out, "u_degrees {}", degree_histogram(&u_degrees))?;
+    writeln!(out, "v_degrees {}", degree_histogram(&v_degrees))?;
+    let survivors = lean_miner::trim(&cs, lean_miner::NTRIMS);
+    writeln!(out, "edges_after_trimming {}", survivors.len())?;
+
+    if options.cycles {
+        let mut lengths: Vec<(usize, usize)> = Vec::new();
+        for cycle in simple_miner::cycles(cs) {
+            match lengths.iter_mut().find(|l| l.0 == cycle.len) {
+                Some(l) => l.1 += 1,
+                None => lengths.push((cycle.len, 1)),
+            }
+        }
+        lengths.sort();
+        let lengths: Vec<String> = lengths.iter().map(|&(len, n)| format!("{}:{}", len, n)).collect();
+        writeln!(out, "cycles {}", lengths.join(" "))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    init_logging();
+
+    let mut args = std::env::args().skip(1);
+    let command = args.next().unwrap_or_default();
+    if command == "-h" || command == "--help" || command == "help" {
+        let _ = writeln!(io::stdout(), "{}", USAGE);
+        return;
+    }
+
+    let result = parse_options(&mut args).and_then(|options| match command.as_str() {
+        "mine" => mine(&options),
+        "verify" => verify_command(&options),
+        "bench" => bench(&options),
+        "inspect-graph" => inspect_graph(&options),
+        "" => Err(Failure::Usage(String::from("missing command"))),
+        _ => Err(Failure::Usage(format!("unknown command: {}", command))),
+    });
+
+    match result {
+        Ok(()) => {}
+        Err(Failure::Failed(message)) => {
+            eprintln!("cuckoo: {}", message);
+            process::exit(EXIT_FAILED);
+        }
+        Err(Failure::Usage(message)) => {
+            eprintln!("cuckoo: {}\n\n{}", message, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+        Err(Failure::Io(message)) => {
+            eprintln!("cuckoo: {}", message);
+            process::exit(EXIT_IO);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_options, Failure};
+
+    fn parse(args: &[&str]) -> Result<super::Options, Failure> {
+        parse_options(&mut args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn edge_bits_out_of_range_are_usage_errors() {
+        for bits in ["6", "30", "64", "-1"].iter() {
+            match parse(&["-b", bits]) {
+                Err(Failure::Usage(message)) => assert_eq!(message, format!("bad value for -b: {}", bits)),
+                _ => panic!("-b {} should be a usage error", bits),
+            }
+        }
+        for bits in ["7", "29"].iter() {
+            let options = parse(&["-v", "cuckaroo", "-b", bits]).ok().unwrap();
+            assert_eq!(options.challenge.params.edge_bits, bits.parse::<u32>().unwrap());
+        }
+    }
+}
@@ -8,7 +8,10 @@ extern crate cuckoo_http;
 use stdweb::web::{document, INode};
 
 use cuckoo_http::cuckoo;
+use cuckoo_http::difficulty::Target;
+use cuckoo_http::encoding::{Challenge, Encoding};
 use cuckoo_http::simple_miner;
+use cuckoo_http::solver::SolveControl;
 
 fn main() {
     stdweb::initialize();
@@ -21,32 +24,33 @@ fn main() {
         .unwrap()
         .text_content()
         .unwrap()
-        .parse::<i64>()
-        .unwrap();
-    let difficulty = nl.item(7)
-        .unwrap()
-        .text_content()
-        .unwrap()
-        .parse::<f64>()
+        .parse::<i32>()
         .unwrap();
+    let target = Target::parse(&nl.item(7).unwrap().text_content().unwrap()).unwrap();
     let msg = nl.item(9).unwrap().text_content().unwrap();
+    let params = cuckoo::Params::parse(&nl.item(11).unwrap().text_content().unwrap()).unwrap();
+    let keying = cuckoo::KeyingScheme::parse(&nl.item(13).unwrap().text_content().unwrap()).unwrap();
 
-    let graph_v = cuckoo::hash_header(header.as_bytes());
-
-    let easiness: i32 = ((easipct * cuckoo::NNODES as i64) / 100) as i32;
-    let hash_difficulty: u64 = ((difficulty / 100.0) * std::u64::MAX as f64) as u64;
-    let a = simple_miner::solve(simple_miner::CuckooSolve {
-        graph_v: graph_v,
-        easiness: easiness,
-        hash_difficulty: hash_difficulty,
-        cuckoo: vec![0; (1 + cuckoo::NNODES) as usize],
+    let challenge = Challenge {
+        header: header.clone(),
+        easipct: easipct,
+        target: target,
+        params: params,
+        keying: keying,
+    };
+    let mut last_pct = -1;
+    let mut control = SolveControl::new().on_progress(|p| {
+        let pct = (p.fraction * 100.0) as i32;
+        if pct != last_pct {
+            last_pct = pct;
+            js! { console.log("cuckoo: " + @{pct} + "% searched"); }
+        }
     });
+    // The packed layout keeps the tables small enough for a browser tab
+    let mut arena = simple_miner::Arena::new(simple_miner::Layout::Packed);
+    let a = simple_miner::solve_in(challenge.solve_params(), &mut arena, &mut control).proof();
 
-    let message = a.unwrap()
-        .into_iter()
-        .map(|x| format!("{:x} ", x))
-        .collect::<Vec<_>>()
-        .concat();
+    let message = a.unwrap().to_hex();
 
     js! {
         alert( @{message.clone()} );
@@ -56,7 +60,7 @@ fn main() {
         var xhr = new XMLHttpRequest();
         xhr.open("GET", '/', true);
         xhr.setRequestHeader("X-Cuckoo-Header", @{header});
-        xhr.setRequestHeader("X-Cuckoo-Solution", @{message.trim()});
+        xhr.setRequestHeader("X-Cuckoo-Solution", @{message.clone()});
         xhr.setRequestHeader("X-Cuckoo-Original-Request", @{msg});
 
         xhr.onreadystatechange = function () {
@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::str;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use cuckoo::{KeyingScheme, Params, Proof};
+use difficulty::Target;
+pub use encoding::Challenge;
+use encoding::Encoding;
+use simple_miner::{self, ParallelConfig};
+use solver::{SolveControl, SolveStatus};
+
+const TIMEOUT_SECS: u64 = 30;
+const SOLVE_TIMEOUT_SECS: u64 = 60;
+// Not every challenge has a proof, so a few fresh ones may be needed
+const ATTEMPTS: usize = 8;
+
+#[derive(Clone, Debug)]
+pub struct ClientResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl ClientResponse {
+    // The first header called `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.0.eq_ignore_ascii_case(name))
+            .map(|h| h.1.as_str())
+    }
+}
+
+impl Challenge {
+    // None unless this is a 401 from the gateway with every X-Cuckoo-*
+    // header present and well formed, or failing that a JSON challenge body
+    pub fn from_response(response: &ClientResponse) -> Option<Challenge> {
+        if response.status != 401 {
+            return None;
+        }
+        let from_headers = || {
+            Some(Challenge {
+                header: response.header("X-Cuckoo-Header")?.to_string(),
+                easipct: response.header("X-Cuckoo-Easiness")?.parse().ok()?,
+                target: Target::parse(response.header("X-Cuckoo-Target")?)?,
+                params: Params::parse(response.header("X-Cuckoo-Params")?)?,
+                keying: KeyingScheme::parse(response.header("X-Cuckoo-Keying").unwrap_or("legacy"))?,
+            })
+        };
+        from_headers().or_else(|| Challenge::from_json(str::from_utf8(&response.body).ok()?))
+    }
+}
+
+// host:port and path of an http:// url
+fn split_url(url: &str) -> io::Result<(String, String)> {
+    if !url.starts_with("http://") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only http:// urls are supported",
+        ));
+    }
+    let rest = &url[7..];
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let authority = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    Ok((authority, path.to_string()))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn decode_chunked(mut body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(|| invalid_data("truncated chunk"))?;
+        let size = str::from_utf8(&body[..line_end])
+            .ok()
+            .and_then(|s| usize::from_str_radix(s.split(';').next().unwrap().trim(), 16).ok())
+            .ok_or_else(|| invalid_data("bad chunk size"))?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(out);
+        }
+        if body.len() < size {
+            return Err(invalid_data("truncated chunk"));
+        }
+        out.extend_from_slice(&body[..size]);
+        body = &body[(size + 2).min(body.len())..];
+    }
+}
+
+fn parse_response(raw: &[u8]) -> io::Result<ClientResponse> {
+    let head_end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| invalid_data("response has no end of headers"))?;
+    let head = String::from_utf8_lossy(&raw[..head_end]);
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| invalid_data("bad status line"))?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| {
+            let i = line.find(':')?;
+            Some((line[..i].trim().to_string(), line[i + 1..].trim().to_string()))
+        })
+        .collect();
+    let mut response = ClientResponse {
+        status,
+        headers,
+        body: raw[head_end + 4..].to_vec(),
+    };
+    let chunked = response
+        .header("Transfer-Encoding")
+        .is_some_and(|te| te.eq_ignore_ascii_case("chunked"));
+    if chunked {
+        response.body = decode_chunked(&response.body)?;
+    }
+    Ok(response)
+}
+
+// Blocking HTTP/1.1 client that gets itself through a cuckoo-http gateway.
+// When a request is answered with a JSON challenge, the client solves it
+// and sends the request again with the proof. Clearance tokens handed out
+// by the gateway are kept per host and sent until they expire.
+pub struct Client {
+    timeout: Duration,
+    solve_timeout: Duration,
+    attempts: usize,
+    config: ParallelConfig,
+    // host:port to clearance token and when it runs out
+    clearances: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl Client {
+    pub fn new() -> Client {
+        Client {
+            timeout: Duration::new(TIMEOUT_SECS, 0),
+            solve_timeout: Duration::new(SOLVE_TIMEOUT_SECS, 0),
+            attempts: ATTEMPTS,
+            config: ParallelConfig::default(),
+            clearances: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Read and write timeout for each connection
+    pub fn timeout(mut self, timeout: Duration) -> Client {
+        self.timeout = timeout;
+        self
+    }
+
+    // How long to spend on one challenge before asking for another
+    pub fn solve_timeout(mut self, timeout: Duration) -> Client {
+        self.solve_timeout = timeout;
+        self
+    }
+
+    // How many challenges to try before giving up on a request
+    pub fn attempts(mut self, attempts: usize) -> Client {
+        self.attempts = attempts;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Client {
+        self.config.threads = threads;
+        self
+    }
+
+    pub fn get(&self, url: &str) -> io::Result<ClientResponse> {
+        self.request("GET", url, &[], &[])
+    }
+
+    pub fn post(&self, url: &str, body: &[u8]) -> io::Result<ClientResponse> {
+        self.request("POST", url, &[], body)
+    }
+
+    // Sends the request, solving challenges along the way. A response that
+    // isn't a challenge is returned as is, whatever its status.
+    pub fn request(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<ClientResponse> {
+        let (authority, path) = split_url(url)?;
+        let mut proof: Option<(String, Proof)> = None;
+        let mut attempts = 0;
+        loop {
+            let mut extra = Vec::new();
+            match proof {
+                Some((ref header, ref proof)) => {
+                    extra.push(("X-Cuckoo-Header", header.clone()));
+                    extra.push(("X-Cuckoo-Solution", proof.to_hex()));
+                }
+                None => {
+                    if let Some(token) = self.clearance(&authority) {
+                        extra.push(("X-Cuckoo-Clearance", token));
+                    }
+                }
+            }
+            let response = self.send(&authority, method, &path, headers, &extra, body)?;
+
+            let challenge = match Challenge::from_response(&response) {
+                Some(challenge) => challenge,
+                None => {
+                    self.keep_clearance(&authority, &response);
+                    return Ok(response);
+                }
+            };
+            // Whatever we sent didn't get us through
+            self.clearances.lock().unwrap().remove(&authority);
+            if attempts == self.attempts {
+                return Err(io::Error::other(format!(
+                    "no proof found after {} challenges",
+                    attempts
+                )));
+            }
+            attempts += 1;
+            proof = self.solve(&challenge)?.map(|p| (challenge.header, p));
+        }
+    }
+
+    // None if the challenge has no proof or took too long. Either way a
+    // fresh challenge is the way forward.
+    pub fn solve(&self, challenge: &Challenge) -> io::Result<Option<Proof>> {
+        let started = Instant::now();
+        let mut control = SolveControl::new().with_timeout(self.solve_timeout);
+        let status = simple_miner::solve_parallel_with(challenge.solve_params(), &self.config, &mut control);
+        debug!(
+            "challenge solved challenge={} params={} solved={} solve_ms={}",
+            challenge.header,
+            challenge.params,
+            status.proof().is_some(),
+            started.elapsed().as_secs() * 1000 + started.elapsed().subsec_millis() as u64
+        );
+        match status {
+            SolveStatus::Solved(proof) => Ok(Some(proof)),
+            SolveStatus::Exhausted | SolveStatus::TimedOut => Ok(None),
+            SolveStatus::Cancelled => Err(io::Error::new(io::ErrorKind::Interrupted, "solve cancelled")),
+        }
+    }
+
+    fn clearance(&self, authority: &str) -> Option<String> {
+        let mut clearances = self.clearances.lock().unwrap();
+        match clearances.get(authority) {
+            Some(&(ref token, expires)) if expires > Instant::now() => return Some(token.clone()),
+            Some(_) => {}
+            None => return None,
+        }
+        clearances.remove(authority);
+        None
+    }
+
+    fn keep_clearance(&self, authority: &str, response: &ClientResponse) {
+        let token = match response.header("X-Cuckoo-Clearance") {
+            Some(token) => token.to_string(),
+            None => return,
+        };
+        let max_age = response
+            .header("X-Cuckoo-Clearance-Max-Age")
+            .and_then(|age| age.parse::<u64>().ok())
+            .unwrap_or(0);
+        self.clearances.lock().unwrap().insert(
+            authority.to_string(),
+            (token, Instant::now() + Duration::new(max_age, 0)),
+        );
+    }
+
+    fn send(
+        &self,
+        authority: &str,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        extra: &[(&str, String)],
+        body: &[u8],
+    ) -> io::Result<ClientResponse> {
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method, path, authority);
+        if !headers.iter().any(|h| h.0.eq_ignore_ascii_case("accept")) {
+            request.push_str("Accept: application/json\r\n");
+        }
+        for &(name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        for &(name, ref value) in extra {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", body.len()));
+
+        let mut s = TcpStream::connect(authority)?;
+        s.set_read_timeout(Some(self.timeout))?;
+        s.set_write_timeout(Some(self.timeout))?;
+        s.write_all(request.as_bytes())?;
+        s.write_all(body)?;
+        let mut raw = Vec::new();
+        s.read_to_end(&mut raw)?;
+        parse_response(&raw)
+    }
+}
+
+impl Default for Client {
+    fn default() -> Client {
+        Client::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use client::{parse_response, split_url, Client};
+    use cuckoo::{Params, Variant};
+    use difficulty::Target;
+    use gateway::GatewayBuilder;
+    use handler::{Request, Response};
+    use http_server::{AssetSource, PuzzlePolicy};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn parses_urls_and_responses() {
+        assert_eq!(
+            split_url("http://example.com/a?b").unwrap(),
+            (String::from("example.com:80"), String::from("/a?b"))
+        );
+        assert_eq!(
+            split_url("http://127.0.0.1:8080").unwrap(),
+            (String::from("127.0.0.1:8080"), String::from("/"))
+        );
+        assert!(split_url("https://example.com/").is_err());
+
+        let r = parse_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;x=y\r\nde\r\n0\r\n\r\n",
+        ).unwrap();
+        assert_eq!(r.status, 200);
+        assert_eq!(r.header("transfer-encoding"), Some("chunked"));
+        assert_eq!(r.body, b"abcde");
+    }
+
+    #[test]
+    fn solves_challenges_and_reuses_clearance() {
+        let verified = Arc::new(Mutex::new(0));
+        let count = verified.clone();
+        let handle = GatewayBuilder::new()
+            .listen("127.0.0.1:0")
+            .assets(AssetSource::Memory {
+                index: Vec::new(),
+                js: String::new(),
+                wasm: Vec::new(),
+            })
+            .policy(PuzzlePolicy {
+                params: Params::new(Variant::Cuckoo, 16),
+                ..PuzzlePolicy::default()
+            })
+            .attack_policy(PuzzlePolicy {
+                params: Params::new(Variant::Cuckoo, 16),
+                target: Target::from_expected_attempts(2),
+                ..PuzzlePolicy::default()
+            })
+            .clearance_ttl(Duration::new(60, 0))
+            .handler(|r: &Request| Ok(Response::new(200, "text/plain", r.path().as_bytes().to_vec())))
+            .on_verify(move |e| {
+                if e.rejected.is_none() {
+                    *count.lock().unwrap() += 1;
+                }
+            })
+            .build()
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let base = format!("http://{}", handle.local_addr());
+
+        let client = Client::new().attempts(50);
+        let r = client.get(&format!("{}/one", base)).unwrap();
+        assert_eq!((r.status, &r.body[..]), (200, &b"/one"[..]));
+        let r = client.get(&format!("{}/two", base)).unwrap();
+        assert_eq!((r.status, &r.body[..]), (200, &b"/two"[..]));
+        assert_eq!(*verified.lock().unwrap(), 1);
+
+        // Clearance doesn't count under attack, so the client solves again
+        handle.state().set_under_attack(true);
+        let r = client.get(&format!("{}/three", base)).unwrap();
+        assert_eq!(r.status, 200);
+        assert_eq!(*verified.lock().unwrap(), 2);
+        handle.shutdown().unwrap();
+    }
+}
@@ -1,22 +1,149 @@
 use blake2::digest::generic_array::GenericArray;
 use blake2::digest::generic_array::typenum::U64;
-use blake2::{Blake2b, Digest};
+use blake2::digest::{self, VariableOutput};
+use blake2::{Blake2b, Digest, VarBlake2b};
 
+use std::fmt;
 use std::hash::Hash;
 use std::hash::Hasher;
 use std::num::Wrapping;
 use std::u64;
 
+use difficulty::Target;
+
 pub const EDGEBITS: i32 = 22;
 pub const NEDGES: i32 = 1 << EDGEBITS;
 pub const NODEBITS: i32 = EDGEBITS + 1;
 pub const NNODES: i32 = 1 << NODEBITS;
 pub const EDGEMASK: i32 = NEDGES - 1;
 pub const PROOFSIZE: usize = 42;
+pub const HEADERLEN: usize = 80;
+// Node indices up to 2^(MAX_EDGEBITS + 1) still fit in an i32
+pub const MAX_EDGEBITS: u32 = 29;
+pub const EDGE_BLOCK_BITS: u32 = 6;
+pub const EDGE_BLOCK_SIZE: usize = 1 << EDGE_BLOCK_BITS;
+pub const EDGE_BLOCK_MASK: i32 = (EDGE_BLOCK_SIZE as i32) - 1;
 
 pub type Proof = [i32; PROOFSIZE];
 
-#[derive(Debug, Eq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum KeyingScheme {
+    // First 32 bytes of a blake2b-512 digest, as served to the wasm miner
+    #[default]
+    Legacy,
+    // The whole of a blake2b-256 digest. The reference Cuckoo Cycle solvers
+    // key their graphs this way, over header_with_nonce.
+    Blake2b256,
+}
+
+impl KeyingScheme {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            KeyingScheme::Legacy => "legacy",
+            KeyingScheme::Blake2b256 => "blake2b256",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<KeyingScheme> {
+        match s {
+            "legacy" => Some(KeyingScheme::Legacy),
+            "blake2b256" => Some(KeyingScheme::Blake2b256),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Variant {
+    // Original bipartite graph, nonces bounded by easiness
+    Cuckoo,
+    // Every nonce below 2^edge_bits is an edge; proofs must also xor to zero
+    Cuckatoo,
+    // Like Cuckatoo, but edges come out of 64-nonce siphash blocks
+    Cuckaroo,
+}
+
+impl Variant {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Variant::Cuckoo => "cuckoo",
+            Variant::Cuckatoo => "cuckatoo",
+            Variant::Cuckaroo => "cuckaroo",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Params {
+    pub variant: Variant,
+    pub edge_bits: u32,
+}
+
+impl Params {
+    pub fn new(variant: Variant, edge_bits: u32) -> Params {
+        assert!(edge_bits > EDGE_BLOCK_BITS && edge_bits <= MAX_EDGEBITS);
+        Params {
+            variant,
+            edge_bits,
+        }
+    }
+
+    // Accepts the Display form, e.g. "cuckoo22" or "cuckaroo29"
+    pub fn parse(s: &str) -> Option<Params> {
+        let split = s.find(|c: char| c.is_ascii_digit())?;
+        let variant = match &s[..split] {
+            "cuckoo" => Variant::Cuckoo,
+            "cuckatoo" => Variant::Cuckatoo,
+            "cuckaroo" => Variant::Cuckaroo,
+            _ => return None,
+        };
+        let edge_bits = s[split..].parse::<u32>().ok()?;
+        if edge_bits <= EDGE_BLOCK_BITS || edge_bits > MAX_EDGEBITS {
+            return None;
+        }
+        Some(Params::new(variant, edge_bits))
+    }
+
+    pub fn nedges(&self) -> i32 {
+        1 << self.edge_bits
+    }
+
+    pub fn nnodes(&self) -> i32 {
+        2 * self.nedges()
+    }
+
+    pub fn edge_mask(&self) -> i32 {
+        self.nedges() - 1
+    }
+
+    // Exclusive upper bound on nonces for a given easiness
+    pub fn nonce_limit(&self, easiness: i32) -> i32 {
+        match self.variant {
+            Variant::Cuckoo => easiness,
+            Variant::Cuckatoo | Variant::Cuckaroo => {
+                if easiness < self.nedges() {
+                    easiness
+                } else {
+                    self.nedges()
+                }
+            }
+        }
+    }
+}
+
+impl Default for Params {
+    fn default() -> Params {
+        Params::new(Variant::Cuckoo, EDGEBITS as u32)
+    }
+}
+
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.variant.name(), self.edge_bits)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq)]
 pub struct Edge {
     pub u: i32,
     pub v: i32,
@@ -40,7 +167,7 @@ fn u8(a: u8) -> u64 {
     return (a as u64) & 0xff;
 }
 
-fn u8to64(p: GenericArray<u8, U64>, i: usize) -> u64 {
+fn u8to64(p: &[u8], i: usize) -> u64 {
     return u8(p[i]) | u8(p[i + 1]) << 8 | u8(p[i + 2]) << 16 | u8(p[i + 3]) << 24
         | u8(p[i + 4]) << 32 | u8(p[i + 5]) << 40 | u8(p[i + 6]) << 48
         | u8(p[i + 7]) << 56;
@@ -49,39 +176,52 @@ fn u8to64(p: GenericArray<u8, U64>, i: usize) -> u64 {
 pub fn hash_header(header: &[u8]) -> [u64; 4] {
     let mut hasher = Blake2b::new();
     hasher.input(header);
-    let result = hasher.result();
-    return [
-        u8to64(result, 0),
-        u8to64(result, 8),
-        u8to64(result, 16),
-        u8to64(result, 24),
-    ];
+    let result: GenericArray<u8, U64> = hasher.result();
+    [
+        u8to64(&result, 0),
+        u8to64(&result, 8),
+        u8to64(&result, 16),
+        u8to64(&result, 24),
+    ]
 }
 
-pub fn proof_satisfies_difficulty(proof: &Proof, difficulty: u64) -> bool {
-    let mut hasher = Blake2b::new();
-    let mut p8 = Vec::new();
-    for i in proof.iter() {
-        let i_be = i.to_be();
-        let new_bytes = vec![
-            ((i_be >> 24) & 0xff) as u8,
-            ((i_be >> 16) & 0xff) as u8,
-            ((i_be >> 8) & 0xff) as u8,
-            (i_be & 0xff) as u8,
-        ];
-        p8.extend(new_bytes);
-    }
-    hasher.input(&p8);
-
-    let result = hasher.result();
+pub fn blake2b256_hash_header(header: &[u8]) -> [u64; 4] {
+    let mut hasher = VarBlake2b::new(32).unwrap();
+    digest::Input::input(&mut hasher, header);
+    let result = hasher.vec_result();
+    [
+        u8to64(&result, 0),
+        u8to64(&result, 8),
+        u8to64(&result, 16),
+        u8to64(&result, 24),
+    ]
+}
 
-    // This is not ideal, but works
-    let mut acc: u64 = 0;
-    for i in 0..8 {
-        acc |= u8(result[i * 4]) << (i * 8);
+pub fn header_keys(header: &[u8], scheme: KeyingScheme) -> [u64; 4] {
+    match scheme {
+        KeyingScheme::Legacy => hash_header(header),
+        KeyingScheme::Blake2b256 => blake2b256_hash_header(header),
     }
+}
+
+// Reference header layout: the header zero-padded (or truncated) to
+// HEADERLEN bytes, with the little-endian nonce in the last four bytes
+pub fn header_with_nonce(header: &[u8], nonce: u32) -> [u8; HEADERLEN] {
+    let mut buf = [0u8; HEADERLEN];
+    let len = header.len().min(HEADERLEN);
+    buf[..len].copy_from_slice(&header[..len]);
+    buf[HEADERLEN - 4..].copy_from_slice(&[
+        nonce as u8,
+        (nonce >> 8) as u8,
+        (nonce >> 16) as u8,
+        (nonce >> 24) as u8,
+    ]);
+    buf
+}
 
-    return acc < difficulty;
+// Keys for a header and nonce the way the reference solvers derive them
+pub fn reference_keys(header: &[u8], nonce: u32) -> [u64; 4] {
+    blake2b256_hash_header(&header_with_nonce(header, nonce))
 }
 
 #[inline]
@@ -139,6 +279,239 @@ pub fn sipnode(v: [u64; 4], nonce: i32, uorv: i32) -> i32 {
     return (siphash24(v, (2 * nonce + uorv) as u64) as i32) & EDGEMASK;
 }
 
+// siphash24 of each of `nonces`, a lane per nonce. The lanes are kept apart
+// so the compiler can vectorize this on targets without a hand-written path.
+#[inline]
+fn siphash24_lanes(v: [u64; 4], nonces: [u64; 4]) -> [u64; 4] {
+    let mut out = [0; 4];
+    for i in 0..4 {
+        out[i] = siphash24(v, nonces[i]);
+    }
+    out
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use std::arch::x86_64::*;
+
+    macro_rules! rotl {
+        ($x:expr, 32) => {
+            _mm256_shuffle_epi32($x, 0b10_11_00_01)
+        };
+        ($x:expr, 16) => {
+            _mm256_shuffle_epi8(
+                $x,
+                _mm256_set_epi8(
+                    13, 12, 11, 10, 9, 8, 15, 14, 5, 4, 3, 2, 1, 0, 7, 6,
+                    13, 12, 11, 10, 9, 8, 15, 14, 5, 4, 3, 2, 1, 0, 7, 6,
+                ),
+            )
+        };
+        ($x:expr, $b:expr) => {
+            _mm256_or_si256(_mm256_slli_epi64($x, $b), _mm256_srli_epi64($x, 64 - $b))
+        };
+    }
+
+    macro_rules! sipround {
+        ($v0:ident, $v1:ident, $v2:ident, $v3:ident) => {
+            $v0 = _mm256_add_epi64($v0, $v1);
+            $v2 = _mm256_add_epi64($v2, $v3);
+            $v1 = rotl!($v1, 13);
+            $v3 = rotl!($v3, 16);
+            $v1 = _mm256_xor_si256($v1, $v0);
+            $v3 = _mm256_xor_si256($v3, $v2);
+            $v0 = rotl!($v0, 32);
+            $v2 = _mm256_add_epi64($v2, $v1);
+            $v0 = _mm256_add_epi64($v0, $v3);
+            $v1 = rotl!($v1, 17);
+            $v3 = rotl!($v3, 21);
+            $v1 = _mm256_xor_si256($v1, $v2);
+            $v3 = _mm256_xor_si256($v3, $v0);
+            $v2 = rotl!($v2, 32);
+        };
+    }
+
+    // Hashes each vector of nonces with its own state. The states don't
+    // depend on each other, so the compiler interleaves their rounds and one
+    // hides the other's latency.
+    macro_rules! siphash {
+        ($v:ident, [$($n:ident),*]) => {{
+            let k0 = _mm256_set1_epi64x($v[0] as i64);
+            let k1 = _mm256_set1_epi64x($v[1] as i64);
+            let k2 = _mm256_set1_epi64x($v[2] as i64);
+            let k3 = _mm256_set1_epi64x($v[3] as i64);
+            let ff = _mm256_set1_epi64x(0xff);
+            ($({
+                let mut v0 = k0;
+                let mut v1 = k1;
+                let mut v2 = k2;
+                let mut v3 = _mm256_xor_si256(k3, $n);
+                sipround!(v0, v1, v2, v3);
+                sipround!(v0, v1, v2, v3);
+                v0 = _mm256_xor_si256(v0, $n);
+                v2 = _mm256_xor_si256(v2, ff);
+                sipround!(v0, v1, v2, v3);
+                sipround!(v0, v1, v2, v3);
+                sipround!(v0, v1, v2, v3);
+                sipround!(v0, v1, v2, v3);
+                _mm256_xor_si256(_mm256_xor_si256(v0, v1), _mm256_xor_si256(v2, v3))
+            },)*)
+        }};
+    }
+
+    // Callers must check for AVX2 first
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn siphash24_x4(v: [u64; 4], nonces: [u64; 4]) -> [u64; 4] {
+        let n = _mm256_loadu_si256(nonces.as_ptr() as *const __m256i);
+        let (h,) = siphash!(v, [n]);
+        let mut out = [0u64; 4];
+        _mm256_storeu_si256(out.as_mut_ptr() as *mut __m256i, h);
+        out
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn siphash24_x8(v: [u64; 4], nonces: [u64; 8]) -> [u64; 8] {
+        let lo = _mm256_loadu_si256(nonces.as_ptr() as *const __m256i);
+        let hi = _mm256_loadu_si256(nonces[4..].as_ptr() as *const __m256i);
+        let (h_lo, h_hi) = siphash!(v, [lo, hi]);
+        let mut out = [0u64; 8];
+        _mm256_storeu_si256(out.as_mut_ptr() as *mut __m256i, h_lo);
+        _mm256_storeu_si256(out[4..].as_mut_ptr() as *mut __m256i, h_hi);
+        out
+    }
+}
+
+// siphash24 of four nonces at once, using AVX2 when the CPU has it
+pub fn siphash24_x4(v: [u64; 4], nonces: [u64; 4]) -> [u64; 4] {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return unsafe { avx2::siphash24_x4(v, nonces) };
+        }
+    }
+    siphash24_lanes(v, nonces)
+}
+
+pub fn siphash24_x8(v: [u64; 4], nonces: [u64; 8]) -> [u64; 8] {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return unsafe { avx2::siphash24_x8(v, nonces) };
+        }
+    }
+    let lo = siphash24_x4(v, [nonces[0], nonces[1], nonces[2], nonces[3]]);
+    let hi = siphash24_x4(v, [nonces[4], nonces[5], nonces[6], nonces[7]]);
+    [lo[0], lo[1], lo[2], lo[3], hi[0], hi[1], hi[2], hi[3]]
+}
+
+// Hashes the whole 64-nonce block containing `edge` with a single chained
+// siphash state, xoring every hash but the last with the last one, and
+// returns the hash belonging to `edge`
+pub fn sipblock(v: [u64; 4], edge: i32, buf: &mut [u64; EDGE_BLOCK_SIZE]) -> u64 {
+    let mut v0: Wrapping<u64> = Wrapping(v[0]);
+    let mut v1: Wrapping<u64> = Wrapping(v[1]);
+    let mut v2: Wrapping<u64> = Wrapping(v[2]);
+    let mut v3: Wrapping<u64> = Wrapping(v[3]);
+
+    let edge0 = (edge & !EDGE_BLOCK_MASK) as u64;
+    for (i, hash) in buf.iter_mut().enumerate() {
+        let nonce = Wrapping(edge0 + i as u64);
+        v3 ^= nonce;
+        sipround(&mut v0, &mut v1, &mut v2, &mut v3);
+        sipround(&mut v0, &mut v1, &mut v2, &mut v3);
+        v0 ^= nonce;
+        v2 ^= Wrapping(0xff);
+        sipround(&mut v0, &mut v1, &mut v2, &mut v3);
+        sipround(&mut v0, &mut v1, &mut v2, &mut v3);
+        sipround(&mut v0, &mut v1, &mut v2, &mut v3);
+        sipround(&mut v0, &mut v1, &mut v2, &mut v3);
+        *hash = ((v0 ^ v1) ^ (v2 ^ v3)).0;
+    }
+
+    let last = buf[EDGE_BLOCK_SIZE - 1];
+    for hash in buf[..EDGE_BLOCK_SIZE - 1].iter_mut() {
+        *hash ^= last;
+    }
+    buf[(edge & EDGE_BLOCK_MASK) as usize]
+}
+
+// Edges hashed together by EdgeGen, two siphash lanes each
+const EDGE_BATCH: i32 = 4;
+
+// Produces edges for any variant. Cuckaroo edges are computed a block at a
+// time, so walking nonces in order only hashes each block once. The other
+// variants hash EDGE_BATCH edges at a time with siphash24_x8 when nonces are
+// walked densely in order, and one at a time otherwise.
+pub struct EdgeGen {
+    params: Params,
+    v: [u64; 4],
+    block: [u64; EDGE_BLOCK_SIZE],
+    block_start: i32,
+    batch: [Edge; EDGE_BATCH as usize],
+    batch_start: i32,
+}
+
+impl EdgeGen {
+    pub fn new(params: Params, v: [u64; 4]) -> EdgeGen {
+        EdgeGen {
+            params,
+            v,
+            block: [0; EDGE_BLOCK_SIZE],
+            block_start: -1,
+            batch: [Edge { u: 0, v: 0 }; EDGE_BATCH as usize],
+            batch_start: -1,
+        }
+    }
+
+    fn fill_batch(&mut self, start: i32) {
+        let mask = self.params.edge_mask();
+        let first = 2 * start as u64;
+        let mut nonces = [0; 8];
+        for (i, n) in nonces.iter_mut().enumerate() {
+            *n = first + i as u64;
+        }
+        let h = siphash24_x8(self.v, nonces);
+        for (i, e) in self.batch.iter_mut().enumerate() {
+            e.u = (h[2 * i] as i32) & mask;
+            e.v = (h[2 * i + 1] as i32) & mask;
+        }
+        self.batch_start = start;
+    }
+
+    #[inline]
+    pub fn edge(&mut self, nonce: i32) -> Edge {
+        let mask = self.params.edge_mask();
+        match self.params.variant {
+            Variant::Cuckoo | Variant::Cuckatoo => {
+                let start = nonce & !(EDGE_BATCH - 1);
+                if start != self.batch_start {
+                    if nonce != start && start != self.batch_start + EDGE_BATCH {
+                        // Sparse or out of order; a batch would mostly go to waste
+                        return Edge {
+                            u: (siphash24(self.v, (2 * nonce as i64) as u64) as i32) & mask,
+                            v: (siphash24(self.v, (2 * nonce as i64 + 1) as u64) as i32) & mask,
+                        };
+                    }
+                    self.fill_batch(start);
+                }
+                self.batch[(nonce - start) as usize]
+            }
+            Variant::Cuckaroo => {
+                let start = nonce & !EDGE_BLOCK_MASK;
+                if start != self.block_start {
+                    sipblock(self.v, nonce, &mut self.block);
+                    self.block_start = start;
+                }
+                let h = self.block[(nonce & EDGE_BLOCK_MASK) as usize];
+                Edge {
+                    u: (h as i32) & mask,
+                    v: ((h >> 32) as i32) & mask,
+                }
+            }
+        }
+    }
+}
+
 pub fn sipedge(v: [u64; 4], nonce: i32) -> Edge {
     return Edge {
         u: sipnode(v, nonce, 0),
@@ -146,58 +519,331 @@ pub fn sipedge(v: [u64; 4], nonce: i32) -> Edge {
     };
 }
 
-pub fn verify(v: [u64; 4], nonces: Proof, easiness: i32, hash_difficulty: u64) -> bool {
-    if !proof_satisfies_difficulty(&nonces, hash_difficulty) {
+pub fn verify(v: [u64; 4], nonces: Proof, easiness: i32, target: Target) -> bool {
+    verify_params(Params::default(), v, nonces, easiness, target)
+}
+
+pub fn verify_params(
+    params: Params,
+    v: [u64; 4],
+    nonces: Proof,
+    easiness: i32,
+    target: Target,
+) -> bool {
+    // Cheapest checks first, so junk costs next to nothing
+    let limit = params.nonce_limit(easiness);
+    for n in 0..PROOFSIZE {
+        if nonces[n] < 0 || nonces[n] >= limit || (n != 0 && nonces[n] <= nonces[n - 1]) {
+            return false;
+        }
+    }
+    if !target.is_met_by(&nonces) {
         return false;
     }
 
     let mut us: [i32; PROOFSIZE] = [0; PROOFSIZE];
     let mut vs: [i32; PROOFSIZE] = [0; PROOFSIZE];
+    let mut edges = EdgeGen::new(params, v);
+    // Every node of a cycle is visited exactly twice
+    let mut xor0: i32 = 0;
+    let mut xor1: i32 = 0;
+    for n in 0..PROOFSIZE {
+        let e = edges.edge(nonces[n]);
+        us[n] = e.u;
+        vs[n] = e.v;
+        xor0 ^= e.u;
+        xor1 ^= e.v;
+    }
 
-    let mut i: usize = 0;
+    if params.variant != Variant::Cuckoo && (xor0 | xor1) != 0 {
+        return false;
+    }
+    is_cycle(&us, &vs)
+}
 
-    for n in 0..PROOFSIZE {
-        if nonces[n] >= easiness || (n != 0 && nonces[n] <= nonces[n - 1]) {
+// Pairs up the two edges at each node, given (node, edge) sorted by node.
+// False unless every node has exactly two.
+fn pair_edges(sorted: &[(i32, usize); PROOFSIZE], other: &mut [usize; PROOFSIZE]) -> bool {
+    for k in (0..PROOFSIZE).step_by(2) {
+        let (a, b) = (sorted[k], sorted[k + 1]);
+        if a.0 != b.0 || (k + 2 < PROOFSIZE && sorted[k + 2].0 == a.0) {
             return false;
         }
-        us[n] = sipnode(v, nonces[n], 0);
-        vs[n] = sipnode(v, nonces[n], 1);
+        other[a.1] = b.1;
+        other[b.1] = a.1;
     }
+    true
+}
+
+// Whether edges us[i]-vs[i] make up one cycle through all of them. Sorting
+// finds each node's two edges in O(n log n), then the cycle is walked
+// from the first edge.
+fn is_cycle(us: &[i32; PROOFSIZE], vs: &[i32; PROOFSIZE]) -> bool {
+    let mut by_u = [(0, 0); PROOFSIZE];
+    let mut by_v = [(0, 0); PROOFSIZE];
+    for i in 0..PROOFSIZE {
+        by_u[i] = (us[i], i);
+        by_v[i] = (vs[i], i);
+    }
+    by_u.sort_unstable();
+    by_v.sort_unstable();
 
-    let mut n: usize = PROOFSIZE;
+    let mut u_other = [0; PROOFSIZE];
+    let mut v_other = [0; PROOFSIZE];
+    if !pair_edges(&by_u, &mut u_other) || !pair_edges(&by_v, &mut v_other) {
+        return false;
+    }
 
+    let mut i = 0;
+    let mut n = 0;
     loop {
-        let mut j: usize = i;
-        for k in 0..PROOFSIZE {
-            // find unique other j with same vs[j]
-            if k != i && vs[k] == vs[i] {
-                if j != i {
-                    return false;
-                }
-                j = k;
+        i = u_other[v_other[i]];
+        n += 2;
+        if i == 0 {
+            return n == PROOFSIZE;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cuckoo::{
+        hash_header, header_keys, header_with_nonce, is_cycle, reference_keys, siphash24, siphash24_lanes, siphash24_x4,
+        siphash24_x8, sipround,
+        verify_params, Edge, EdgeGen, KeyingScheme, Params, Proof, Variant, HEADERLEN, MAX_EDGEBITS,
+        PROOFSIZE,
+    };
+    use difficulty::Target;
+    use std::num::Wrapping;
+
+    // Standard SipHash-2-4 over a whole message, built from the same
+    // sipround the graph hash uses
+    fn siphash24_msg(k0: u64, k1: u64, msg: &[u8]) -> u64 {
+        let mut v0 = Wrapping(k0 ^ 0x736f6d6570736575);
+        let mut v1 = Wrapping(k1 ^ 0x646f72616e646f6d);
+        let mut v2 = Wrapping(k0 ^ 0x6c7967656e657261);
+        let mut v3 = Wrapping(k1 ^ 0x7465646279746573);
+
+        let mut blocks: Vec<u64> = msg.chunks(8)
+            .filter(|c| c.len() == 8)
+            .map(|c| c.iter().rev().fold(0, |acc, b| (acc << 8) | *b as u64))
+            .collect();
+        let tail = &msg[msg.len() & !7..];
+        let last = tail.iter()
+            .rev()
+            .fold(0, |acc, b| (acc << 8) | *b as u64);
+        blocks.push(last | ((msg.len() as u64) << 56));
+
+        for m in blocks {
+            v3 ^= Wrapping(m);
+            sipround(&mut v0, &mut v1, &mut v2, &mut v3);
+            sipround(&mut v0, &mut v1, &mut v2, &mut v3);
+            v0 ^= Wrapping(m);
+        }
+        v2 ^= Wrapping(0xff);
+        for _ in 0..4 {
+            sipround(&mut v0, &mut v1, &mut v2, &mut v3);
+        }
+        (v0 ^ v1 ^ v2 ^ v3).0
+    }
+
+    #[test]
+    fn sipround_matches_siphash_paper() {
+        // Key 00..0f, from the test vectors accompanying the SipHash paper
+        let k0 = 0x0706050403020100;
+        let k1 = 0x0f0e0d0c0b0a0908;
+        assert_eq!(siphash24_msg(k0, k1, b""), 0x726fdb47dd0e0e31);
+        assert_eq!(
+            siphash24_msg(k0, k1, &[0, 1, 2, 3, 4, 5, 6, 7]),
+            0x93f5f5799a932462
+        );
+    }
+
+    #[test]
+    fn blake2b256_keys() {
+        // blake2b-256("") = 0e5751c026e543b2 e8ab2eb06099daa1
+        //                   d1e5df47778f7787 faab45cdf12fe3a8
+        assert_eq!(
+            header_keys(b"", KeyingScheme::Blake2b256),
+            [
+                0xb243e526c051570e,
+                0xa1da9960b02eabe8,
+                0x87778f7747dfe5d1,
+                0xa8e32ff1cd45abfa,
+            ]
+        );
+        assert!(header_keys(b"", KeyingScheme::Legacy) != header_keys(b"", KeyingScheme::Blake2b256));
+    }
+
+    #[test]
+    fn header_nonce_layout() {
+        let h = header_with_nonce(b"abc", 0x01020304);
+        assert_eq!(&h[..3], b"abc");
+        assert!(h[3..HEADERLEN - 4].iter().all(|b| *b == 0));
+        assert_eq!(&h[HEADERLEN - 4..], &[4, 3, 2, 1]);
+
+        let long = [b'x'; 100];
+        let h = header_with_nonce(&long, 0);
+        assert!(h[..HEADERLEN - 4].iter().all(|b| *b == b'x'));
+    }
+
+    #[test]
+    fn reference_keys_match_published() {
+        // The reference solvers' keys for an empty header with nonce 71,
+        // as published with their cuckaroo19 test proof
+        assert_eq!(
+            reference_keys(b"", 71),
+            [
+                0x23796193872092ea,
+                0xf1017d8a68c4b745,
+                0xd312bd53d2cd307b,
+                0x840acce5833ddc52,
+            ]
+        );
+    }
+
+    #[test]
+    fn batched_siphash_matches_scalar() {
+        let v = hash_header(b"lanes");
+        for &base in [0u64, 1, 0xffff_fff0, u64::MAX - 8].iter() {
+            let nonces = [base, base + 3, base + 1, base + 7, base + 2, base + 8, base + 5, base + 4];
+            let x8 = siphash24_x8(v, nonces);
+            let x4 = siphash24_x4(v, [nonces[0], nonces[1], nonces[2], nonces[3]]);
+            for i in 0..8 {
+                assert_eq!(x8[i], siphash24(v, nonces[i]));
             }
+            assert_eq!(&x4[..], &x8[..4]);
+            assert_eq!(siphash24_lanes(v, [nonces[0], nonces[1], nonces[2], nonces[3]]), x4);
         }
-        if j == i {
-            return false;
+    }
+
+    #[test]
+    fn edge_batches_match_single_edges() {
+        let v = hash_header(b"batches");
+        let params = Params::new(Variant::Cuckatoo, 12);
+        let single = |n: i32| Edge {
+            u: (siphash24(v, 2 * n as u64) as i32) & params.edge_mask(),
+            v: (siphash24(v, 2 * n as u64 + 1) as i32) & params.edge_mask(),
+        };
+        // Dense, sparse and backwards walks take different paths
+        let orders: Vec<Vec<i32>> = vec![
+            (0..params.nedges()).collect(),
+            (0..params.nedges()).filter(|n| n % 3 != 0).collect(),
+            (0..params.nedges()).step_by(7).collect(),
+            (0..params.nedges()).rev().collect(),
+        ];
+        for order in orders.iter() {
+            let mut edges = EdgeGen::new(params, v);
+            for &n in order.iter() {
+                assert!(edges.edge(n) == single(n), "nonce {}", n);
+            }
         }
-        i = j;
-        for k in 0..PROOFSIZE {
-            // find unique other i with same us[i]
-            if k != j && us[k] == us[j] {
-                if i != j {
-                    return false;
-                }
-                i = k;
+    }
+
+    // Edges of cycles with the given lengths, shuffled so no cycle's
+    // edges sit together
+    fn cycle_edges(lengths: &[usize]) -> ([i32; PROOFSIZE], [i32; PROOFSIZE]) {
+        let mut edges = Vec::new();
+        let mut base = 0;
+        for &len in lengths {
+            let m = len as i32 / 2;
+            for j in 0..m {
+                edges.push((base + j, base + j));
+                edges.push((base + (j + 1) % m, base + j));
             }
+            base += m;
         }
-        if i == j {
-            return false;
+        let (mut us, mut vs) = ([0; PROOFSIZE], [0; PROOFSIZE]);
+        for i in 0..PROOFSIZE {
+            let (u, v) = edges[i * 11 % PROOFSIZE];
+            us[i] = u;
+            vs[i] = v;
         }
-        n -= 2;
+        (us, vs)
+    }
 
-        if i == 0 {
-            break;
+    #[test]
+    fn cycle_check() {
+        let (us, vs) = cycle_edges(&[PROOFSIZE]);
+        assert!(is_cycle(&us, &vs));
+        // Every node has degree two but there are two cycles
+        let (us, vs) = cycle_edges(&[20, 22]);
+        assert!(!is_cycle(&us, &vs));
+        // A node with three edges and one with one
+        let (mut us, vs) = cycle_edges(&[PROOFSIZE]);
+        let i = us.iter().position(|&u| u == 1).unwrap();
+        us[i] = 2;
+        assert!(!is_cycle(&us, &vs));
+    }
+
+    #[test]
+    fn params_round_trip() {
+        for p in [
+            Params::default(),
+            Params::new(Variant::Cuckatoo, 29),
+            Params::new(Variant::Cuckaroo, 19),
+        ].iter()
+        {
+            assert_eq!(Params::parse(&format!("{}", p)), Some(*p));
+        }
+        assert_eq!(format!("{}", Params::default()), "cuckoo22");
+        assert_eq!(Params::parse("cuckoo30"), None);
+        assert_eq!(Params::parse("cuckaroo"), None);
+        assert_eq!(Params::parse("siphash29"), None);
+    }
+
+    #[test]
+    fn largest_params() {
+        let v = hash_header(b"largest");
+        for &variant in [Variant::Cuckoo, Variant::Cuckatoo, Variant::Cuckaroo].iter() {
+            let params = Params::new(variant, MAX_EDGEBITS);
+            assert_eq!(Params::parse(&format!("{}", params)), Some(params));
+            assert_eq!(params.nnodes(), 1 << 30);
+            // The highest nonces hash and check without overflowing
+            let mut nonces = [0; PROOFSIZE];
+            for (i, n) in nonces.iter_mut().enumerate() {
+                *n = params.nnodes() - PROOFSIZE as i32 + i as i32;
+            }
+            assert!(!verify_params(params, v, nonces, params.nnodes(), Target::MAX));
+        }
+    }
+
+    // The cuckaroo19 proof published with the reference solvers for an
+    // empty header with nonce 71
+    const CUCKAROO19_PROOF: Proof = [
+        0x45e9, 0x6a59, 0xf1ad, 0x10ef7, 0x129e8, 0x13e58, 0x17936, 0x19f7f, 0x208df, 0x23704,
+        0x24564, 0x27e64, 0x2b828, 0x2bb41, 0x2ffc0, 0x304c5, 0x31f2a, 0x347de, 0x39686, 0x3ab6c,
+        0x429ad, 0x45254, 0x49200, 0x4f8f8, 0x5697f, 0x57ad1, 0x5dd47, 0x607f8, 0x66199, 0x686c7,
+        0x6d5f3, 0x6da7a, 0x6dbdf, 0x6f6bf, 0x6ffbb, 0x7580e, 0x78594, 0x785ac, 0x78b1d, 0x7b80d,
+        0x7c11c, 0x7da35,
+    ];
+
+    // A proof for an empty header with nonce 38. With every nonce an edge,
+    // cuckoo and cuckatoo build the same graph. There is no published proof
+    // to hand for either, so this one was found by lean_miner and pinned.
+    const CUCKATOO19_PROOF: Proof = [
+        0x21ce, 0x5240, 0xd304, 0xd34c, 0xf756, 0xfaf5, 0x16c9f, 0x1a349, 0x1d3d9, 0x2096a,
+        0x22036, 0x2589b, 0x2e2ed, 0x2eb40, 0x2fb3c, 0x376fd, 0x37740, 0x393c0, 0x3ad29, 0x3cf04,
+        0x3f365, 0x41fe2, 0x43a29, 0x454eb, 0x4cf13, 0x4d12c, 0x535ed, 0x57d03, 0x60e81, 0x68fd1,
+        0x6902f, 0x69408, 0x6c2f1, 0x728c8, 0x73e0e, 0x76589, 0x7a037, 0x7adcb, 0x7c4b8, 0x7d746,
+        0x7eae0, 0x7fe67,
+    ];
+
+    #[test]
+    fn known_answer_proofs() {
+        let cases = [
+            (Variant::Cuckaroo, 71, CUCKAROO19_PROOF),
+            (Variant::Cuckatoo, 38, CUCKATOO19_PROOF),
+            (Variant::Cuckoo, 38, CUCKATOO19_PROOF),
+        ];
+        for &(variant, nonce, proof) in cases.iter() {
+            let params = Params::new(variant, 19);
+            let verify = |keys| verify_params(params, keys, proof, params.nedges(), Target::MAX);
+            assert!(verify(reference_keys(b"", nonce)), "{}", params);
+            assert!(!verify(reference_keys(b"", nonce + 1)), "{}", params);
         }
+        let cuckatoo = Params::new(Variant::Cuckatoo, 19);
+        let keys = reference_keys(b"", 71);
+        assert!(!verify_params(cuckatoo, keys, CUCKAROO19_PROOF, cuckatoo.nedges(), Target::MAX));
     }
-    return n == 0;
 }
@@ -0,0 +1,158 @@
+use blake2::digest::{self, VariableOutput};
+use blake2::VarBlake2b;
+
+use std::fmt;
+use std::time::Duration;
+
+use cuckoo::Proof;
+use encoding::Encoding;
+
+const TWO_64: f64 = 18446744073709551616.0;
+
+// blake2b-256 of the proof's canonical bytes, the nonces as 4 byte
+// big-endian integers
+pub fn proof_hash(proof: &Proof) -> [u8; 32] {
+    let mut hasher = VarBlake2b::new(32).unwrap();
+    digest::Input::input(&mut hasher, proof.to_bytes());
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&hasher.vec_result());
+    hash
+}
+
+// How hard a challenge is. A proof meets the target when the top 64 bits
+// of its hash, read as a big-endian number, are at most the target; that
+// is, when the whole 256 bit hash is at most target * 2^192 + 2^192 - 1.
+// Target::MAX accepts every proof, and each halving of the target doubles
+// the proofs a client can expect to find before one is accepted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Target(pub u64);
+
+impl Target {
+    pub const MAX: Target = Target(u64::MAX);
+
+    // The target at which `attempts` proofs are expected per accepted one,
+    // so 1 accepts everything. 0 is treated as 1.
+    pub fn from_expected_attempts(attempts: u64) -> Target {
+        if attempts <= 1 {
+            return Target::MAX;
+        }
+        Target(((1u128 << 64) / attempts as u128 - 1) as u64)
+    }
+
+    // Proofs found per accepted proof, on average
+    pub fn expected_attempts(&self) -> f64 {
+        TWO_64 / (self.0 as f64 + 1.0)
+    }
+
+    // The chance that a proof meets this target
+    pub fn probability(&self) -> f64 {
+        1.0 / self.expected_attempts()
+    }
+
+    // Nearest target accepting `pct` percent of proofs
+    pub fn from_percent(pct: f64) -> Target {
+        if pct >= 100.0 {
+            return Target::MAX;
+        }
+        let t = pct.max(0.0) / 100.0 * TWO_64;
+        Target(if t < 1.0 { 0 } else { t as u64 - 1 })
+    }
+
+    pub fn percent(&self) -> f64 {
+        self.probability() * 100.0
+    }
+
+    // Expected time to an accepted proof, given how long finding one proof
+    // takes on average
+    pub fn expected_solve_time(&self, per_proof: Duration) -> Duration {
+        let secs = per_proof.as_secs() as f64 + per_proof.subsec_nanos() as f64 / 1e9;
+        let total = secs * self.expected_attempts();
+        Duration::new(total as u64, ((total - total.floor()) * 1e9) as u32)
+    }
+
+    // The target expecting an accepted proof after about `solve_time`,
+    // given how long finding one proof takes. Never easier than MAX.
+    pub fn from_solve_time(solve_time: Duration, per_proof: Duration) -> Target {
+        let nanos = |d: Duration| d.as_secs() as u128 * 1_000_000_000 + d.subsec_nanos() as u128;
+        let per = nanos(per_proof);
+        if per == 0 {
+            return Target::MAX;
+        }
+        let attempts = nanos(solve_time) / per;
+        Target::from_expected_attempts(if attempts > u64::MAX as u128 { u64::MAX } else { attempts as u64 })
+    }
+
+    pub fn is_met_by(&self, proof: &Proof) -> bool {
+        let hash = proof_hash(proof);
+        let top = hash[..8].iter().fold(0, |acc, b| acc << 8 | *b as u64);
+        top <= self.0
+    }
+
+    // The decimal form Display writes, and nothing else: no sign and no
+    // leading zeros
+    pub fn parse(s: &str) -> Option<Target> {
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) || (s.len() > 1 && s.starts_with('0')) {
+            return None;
+        }
+        s.parse::<u64>().ok().map(Target)
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cuckoo::PROOFSIZE;
+    use difficulty::{proof_hash, Target};
+    use std::time::Duration;
+
+    #[test]
+    fn converts_between_targets_and_attempts() {
+        assert_eq!(Target::from_expected_attempts(0), Target::MAX);
+        assert_eq!(Target::from_expected_attempts(1), Target::MAX);
+        assert_eq!(Target::from_expected_attempts(2), Target(u64::MAX / 2));
+        assert_eq!(Target::from_expected_attempts(4), Target((1 << 62) - 1));
+        assert_eq!(Target::from_expected_attempts(3), Target(u64::MAX / 3 - 1));
+        assert_eq!(Target::from_expected_attempts(u64::MAX), Target(0));
+        for &attempts in [1, 2, 3, 4, 1000, 1 << 40].iter() {
+            let t = Target::from_expected_attempts(attempts);
+            assert!((t.expected_attempts() / attempts as f64 - 1.0).abs() < 1e-9);
+        }
+
+        assert_eq!(Target::from_percent(100.0), Target::MAX);
+        assert_eq!(Target::from_percent(50.0), Target(u64::MAX / 2));
+        assert_eq!(Target::from_percent(25.0), Target::from_expected_attempts(4));
+        assert_eq!(Target::from_percent(0.0), Target(0));
+        assert!((Target::from_percent(99.9).percent() - 99.9).abs() < 1e-9);
+
+        let second = Duration::new(1, 0);
+        let t = Target::from_solve_time(Duration::new(8, 0), second);
+        assert_eq!(t, Target::from_expected_attempts(8));
+        assert_eq!(t.expected_solve_time(second), Duration::new(8, 0));
+        assert_eq!(Target::from_solve_time(second, Duration::new(0, 0)), Target::MAX);
+
+        assert_eq!(Target::parse(&Target::MAX.to_string()), Some(Target::MAX));
+        assert_eq!(Target::parse("0"), Some(Target(0)));
+        assert_eq!(Target::parse("+1"), None);
+        assert_eq!(Target::parse("01"), None);
+        assert_eq!(Target::parse("99.9"), None);
+        assert_eq!(Target::parse(""), None);
+    }
+
+    #[test]
+    fn compares_the_top_of_the_proof_hash() {
+        let mut proof = [0; PROOFSIZE];
+        for (i, n) in proof.iter_mut().enumerate() {
+            *n = i as i32 * 1000;
+        }
+        let hash = proof_hash(&proof);
+        let top = hash[..8].iter().fold(0, |acc, b| acc << 8 | *b as u64);
+        assert!(Target(top).is_met_by(&proof));
+        assert!(!Target(top - 1).is_met_by(&proof));
+        assert!(Target::MAX.is_met_by(&proof));
+    }
+}
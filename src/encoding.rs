@@ -0,0 +1,557 @@
+use std::str;
+
+use access_log::json_string;
+use cuckoo::{self, KeyingScheme, Params, Proof, PROOFSIZE};
+use difficulty::Target;
+use simple_miner::CuckooSolve;
+use verifier::VerifyJob;
+
+// What a gateway asks a client to solve
+#[derive(Clone, Debug, PartialEq)]
+pub struct Challenge {
+    pub header: String,
+    pub easipct: i32,
+    pub target: Target,
+    pub params: Params,
+    pub keying: KeyingScheme,
+}
+
+impl Challenge {
+    // Same arithmetic the gateway verifies with
+    pub fn solve_params(&self) -> CuckooSolve {
+        CuckooSolve {
+            target: self.target,
+            ..CuckooSolve::new(self.header.as_bytes(), self.keying, self.params, self.easipct)
+        }
+    }
+
+    pub fn verify_job(&self, proof: &Proof) -> VerifyJob {
+        let cs = self.solve_params();
+        VerifyJob {
+            params: cs.params,
+            keys: cs.graph_v,
+            proof: *proof,
+            easiness: cs.easiness,
+            target: cs.target,
+        }
+    }
+
+    pub fn verify(&self, proof: &Proof) -> bool {
+        self.verify_job(proof).verify()
+    }
+}
+
+// Canonical encodings. Every form of a value decodes back to it, and each
+// value has exactly one binary, hex and base64url form; decoding rejects
+// anything else. JSON decoding allows whitespace and fields it doesn't know.
+//
+// Hex is the binary form in lowercase hex unless a type says otherwise.
+// Proof does: its hex form is the one sent in X-Cuckoo-Solution.
+pub trait Encoding: Sized {
+    // Compact binary form
+    fn to_bytes(&self) -> Vec<u8>;
+    fn from_bytes(bytes: &[u8]) -> Option<Self>;
+
+    fn to_json(&self) -> String;
+    fn from_json(json: &str) -> Option<Self>;
+
+    fn to_hex(&self) -> String {
+        hex(&self.to_bytes())
+    }
+
+    fn from_hex(s: &str) -> Option<Self> {
+        Self::from_bytes(&unhex(s)?)
+    }
+
+    // Unpadded, so it can go in urls and headers as is
+    fn to_base64url(&self) -> String {
+        base64url(&self.to_bytes())
+    }
+
+    fn from_base64url(s: &str) -> Option<Self> {
+        Self::from_bytes(&unbase64url(s)?)
+    }
+}
+
+// Binary: the nonces as 4 byte big-endian integers, the bytes the
+// difficulty::proof_hash is taken over. Hex: each nonce in lowercase hex
+// without leading zeros, separated by single spaces, as sent in
+// X-Cuckoo-Solution. JSON: {"nonces":[...]}.
+impl Encoding for Proof {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 * PROOFSIZE);
+        for n in self.iter() {
+            bytes.extend_from_slice(&[(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, *n as u8]);
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Proof> {
+        if bytes.len() != 4 * PROOFSIZE {
+            return None;
+        }
+        let mut proof = [0; PROOFSIZE];
+        for (n, b) in proof.iter_mut().zip(bytes.chunks(4)) {
+            *n = (b[0] as i32) << 24 | (b[1] as i32) << 16 | (b[2] as i32) << 8 | b[3] as i32;
+        }
+        Some(proof)
+    }
+
+    fn to_json(&self) -> String {
+        let nonces: Vec<String> = self.iter().map(|n| n.to_string()).collect();
+        format!("{{\"nonces\":[{}]}}", nonces.join(","))
+    }
+
+    fn from_json(json: &str) -> Option<Proof> {
+        match json_field(&parse_json_object(json)?, "nonces")? {
+            JsonValue::Array(ref nonces) => {
+                proof_from(nonces.iter().map(|n| json_int(n)))
+            }
+            _ => None,
+        }
+    }
+
+    fn to_hex(&self) -> String {
+        let nonces: Vec<String> = self.iter().map(|n| format!("{:x}", n)).collect();
+        nonces.join(" ")
+    }
+
+    fn from_hex(s: &str) -> Option<Proof> {
+        proof_from(s.split(' ').map(|n| {
+            let digits = n.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+            if !digits || n.is_empty() || n.len() > 8 || (n.len() > 1 && n.starts_with('0')) {
+                return None;
+            }
+            // Negative nonces print as their two's complement
+            u32::from_str_radix(n, 16).ok().map(|n| n as i32)
+        }))
+    }
+}
+
+fn proof_from<I: Iterator<Item = Option<i32>>>(nonces: I) -> Option<Proof> {
+    let nonces: Vec<i32> = nonces.collect::<Option<_>>()?;
+    if nonces.len() != PROOFSIZE {
+        return None;
+    }
+    let mut proof = [0; PROOFSIZE];
+    proof.copy_from_slice(&nonces);
+    Some(proof)
+}
+
+// A proof in any of its text forms: JSON, hex or base64url
+pub fn decode_proof(text: &str) -> Option<Proof> {
+    let text = text.trim();
+    if text.starts_with('{') {
+        Proof::from_json(text)
+    } else if text.contains(char::is_whitespace) {
+        Proof::from_hex(text)
+    } else {
+        Proof::from_base64url(text)
+    }
+}
+
+const VARIANTS: [cuckoo::Variant; 3] = [
+    cuckoo::Variant::Cuckoo,
+    cuckoo::Variant::Cuckatoo,
+    cuckoo::Variant::Cuckaroo,
+];
+const KEYINGS: [KeyingScheme; 2] = [KeyingScheme::Legacy, KeyingScheme::Blake2b256];
+const MAX_HEADER_LEN: usize = 0xffff;
+
+// Binary: header length (2 bytes) and header, easipct (4 bytes), target
+// (8 bytes), then one byte each for the variant, edge bits and keying. All
+// big-endian. JSON: the fields of the gateway's 401 body, which may carry
+// others. easipct goes in "easiness", as in X-Cuckoo-Easiness. The target
+// is a decimal string there, as JavaScript numbers can't hold every u64.
+//
+// Headers must fit the 2 byte length, so no form decodes to a longer one
+// and to_bytes panics on one.
+impl Encoding for Challenge {
+    fn to_bytes(&self) -> Vec<u8> {
+        let header = self.header.as_bytes();
+        assert!(header.len() <= MAX_HEADER_LEN, "challenge header too long for its length prefix");
+        let mut bytes = Vec::with_capacity(header.len() + 17);
+        bytes.extend_from_slice(&[(header.len() >> 8) as u8, header.len() as u8]);
+        bytes.extend_from_slice(header);
+        bytes.extend_from_slice(&be_bytes(self.easipct as u32 as u64, 4));
+        bytes.extend_from_slice(&be_bytes(self.target.0, 8));
+        bytes.push(VARIANTS.iter().position(|v| *v == self.params.variant).unwrap() as u8);
+        bytes.push(self.params.edge_bits as u8);
+        bytes.push(KEYINGS.iter().position(|k| *k == self.keying).unwrap() as u8);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Challenge> {
+        if bytes.len() < 2 {
+            return None;
+        }
+        let len = (bytes[0] as usize) << 8 | bytes[1] as usize;
+        if bytes.len() != len + 17 {
+            return None;
+        }
+        let header = str::from_utf8(&bytes[2..2 + len]).ok()?;
+        let rest = &bytes[2 + len..];
+        let variant = VARIANTS.get(rest[12] as usize)?;
+        Some(Challenge {
+            header: header.to_string(),
+            easipct: from_be_bytes(&rest[..4]) as u32 as i32,
+            target: Target(from_be_bytes(&rest[4..12])),
+            params: Params::parse(&format!("{}{}", variant.name(), rest[13]))?,
+            keying: *KEYINGS.get(rest[14] as usize)?,
+        })
+    }
+
+    fn to_json(&self) -> String {
+        let mut json = String::from("{\"header\":");
+        json_string(&mut json, &self.header);
+        json.push_str(&format!(
+            ",\"easiness\":{},\"target\":\"{}\",\"params\":\"{}\",\"keying\":\"{}\"}}",
+            self.easipct,
+            self.target,
+            self.params,
+            self.keying.name()
+        ));
+        json
+    }
+
+    fn from_json(json: &str) -> Option<Challenge> {
+        let fields = parse_json_object(json)?;
+        let text = |name| match json_field(&fields, name) {
+            Some(JsonValue::Str(s)) => Some(s.as_str()),
+            _ => None,
+        };
+        let number = |name| match json_field(&fields, name) {
+            Some(JsonValue::Number(n)) => Some(n.as_str()),
+            _ => None,
+        };
+        let header = text("header")?;
+        if header.len() > MAX_HEADER_LEN {
+            return None;
+        }
+        Some(Challenge {
+            header: header.to_string(),
+            easipct: json_int(number("easiness")?)?,
+            target: Target::parse(text("target")?)?,
+            params: Params::parse(text("params")?)?,
+            keying: KeyingScheme::parse(text("keying").unwrap_or("legacy"))?,
+        })
+    }
+}
+
+// An integer as JSON writes it: no sign but a minus, no leading zeros, no
+// fraction or exponent
+fn json_int(n: &str) -> Option<i32> {
+    let digits = n.strip_prefix('-').unwrap_or(n);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) || (digits.len() > 1 && digits.starts_with('0')) {
+        return None;
+    }
+    n.parse().ok()
+}
+
+fn be_bytes(x: u64, len: usize) -> Vec<u8> {
+    (0..len).rev().map(|i| (x >> (8 * i)) as u8).collect()
+}
+
+fn from_be_bytes(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, b| acc << 8 | *b as u64)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Lowercase only, so each byte string has one encoding
+pub fn unhex(s: &str) -> Option<Vec<u8>> {
+    let lower_hex = |b: u8| b.is_ascii_digit() || (b'a'..=b'f').contains(&b);
+    if !s.len().is_multiple_of(2) || !s.bytes().all(lower_hex) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+pub fn base64url(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() * 4).div_ceil(3));
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let x = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..chunk.len() + 1 {
+            out.push(BASE64URL[(x >> (18 - 6 * i) & 63) as usize] as char);
+        }
+    }
+    out
+}
+
+// Rejects padding and any leftover bits, so each value has one encoding
+pub fn unbase64url(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    for chunk in s.as_bytes().chunks(4) {
+        let mut x: u32 = 0;
+        for (i, c) in chunk.iter().enumerate() {
+            let d = BASE64URL.iter().position(|b| b == c)? as u32;
+            x |= d << (18 - 6 * i);
+        }
+        let len = chunk.len() - 1;
+        if x & (0xffffff >> (8 * len)) != 0 {
+            return None;
+        }
+        for i in 0..len {
+            out.push((x >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(out)
+}
+
+// Just enough JSON for the flat objects above: string, number and
+// array-of-number values. Numbers are kept as written.
+#[derive(Clone, Debug, PartialEq)]
+enum JsonValue {
+    Str(String),
+    Number(String),
+    Array(Vec<String>),
+}
+
+fn json_field<'a>(fields: &'a [(String, JsonValue)], name: &str) -> Option<&'a JsonValue> {
+    fields.iter().find(|f| f.0 == name).map(|f| &f.1)
+}
+
+struct JsonParser<'a> {
+    s: &'a [u8],
+    i: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn skip_space(&mut self) {
+        while self.i < self.s.len() && (self.s[self.i] as char).is_ascii_whitespace() {
+            self.i += 1;
+        }
+    }
+
+    // Consumes `c`, after any whitespace
+    fn eat(&mut self, c: u8) -> bool {
+        self.skip_space();
+        if self.s.get(self.i) == Some(&c) {
+            self.i += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if !self.eat(b'"') {
+            return None;
+        }
+        let mut out = String::new();
+        loop {
+            let start = self.i;
+            while self.i < self.s.len() && self.s[self.i] != b'"' && self.s[self.i] != b'\\' {
+                self.i += 1;
+            }
+            out.push_str(str::from_utf8(&self.s[start..self.i]).ok()?);
+            match *self.s.get(self.i)? {
+                b'"' => {
+                    self.i += 1;
+                    return Some(out);
+                }
+                _ => {
+                    let escaped = *self.s.get(self.i + 1)?;
+                    self.i += 2;
+                    out.push(match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let code = str::from_utf8(self.s.get(self.i..self.i + 4)?).ok()?;
+                            self.i += 4;
+                            ::std::char::from_u32(u32::from_str_radix(code, 16).ok()?)?
+                        }
+                        _ => return None,
+                    });
+                }
+            }
+        }
+    }
+
+    fn number(&mut self) -> Option<String> {
+        self.skip_space();
+        let start = self.i;
+        while self.i < self.s.len() && b"+-.0123456789eE".contains(&self.s[self.i]) {
+            self.i += 1;
+        }
+        let n = str::from_utf8(&self.s[start..self.i]).ok()?;
+        n.parse::<f64>().ok()?;
+        Some(n.to_string())
+    }
+
+    fn value(&mut self) -> Option<JsonValue> {
+        self.skip_space();
+        match *self.s.get(self.i)? {
+            b'"' => self.string().map(JsonValue::Str),
+            b'[' => {
+                self.i += 1;
+                let mut items = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        items.push(self.number()?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        if !self.eat(b',') {
+                            return None;
+                        }
+                    }
+                }
+                Some(JsonValue::Array(items))
+            }
+            _ => self.number().map(JsonValue::Number),
+        }
+    }
+}
+
+fn parse_json_object(json: &str) -> Option<Vec<(String, JsonValue)>> {
+    let mut p = JsonParser {
+        s: json.as_bytes(),
+        i: 0,
+    };
+    let mut fields = Vec::new();
+    if !p.eat(b'{') {
+        return None;
+    }
+    if !p.eat(b'}') {
+        loop {
+            let name = p.string()?;
+            if !p.eat(b':') {
+                return None;
+            }
+            fields.push((name, p.value()?));
+            if p.eat(b'}') {
+                break;
+            }
+            if !p.eat(b',') {
+                return None;
+            }
+        }
+    }
+    p.skip_space();
+    if p.i != p.s.len() {
+        return None;
+    }
+    Some(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use cuckoo::{KeyingScheme, Params, Proof, Variant, PROOFSIZE};
+    use difficulty::Target;
+    use encoding::{base64url, decode_proof, unbase64url, Challenge, Encoding};
+
+    fn proof() -> Proof {
+        let mut proof = [0; PROOFSIZE];
+        for (i, n) in proof.iter_mut().enumerate() {
+            *n = (i as i32 * 0x2f3a1) & 0x7fffffff;
+        }
+        proof[41] = 0x7fffffff;
+        proof
+    }
+
+    #[test]
+    fn proofs_round_trip() {
+        let p = proof();
+        assert_eq!(p.to_bytes().len(), 4 * PROOFSIZE);
+        assert_eq!(&p.to_bytes()[4..8], &[0, 2, 0xf3, 0xa1]);
+        assert!(p.to_hex().starts_with("0 2f3a1 5e742 "));
+        assert!(p.to_json().starts_with("{\"nonces\":[0,193441,"));
+
+        assert_eq!(Proof::from_bytes(&p.to_bytes()), Some(p));
+        assert_eq!(Proof::from_hex(&p.to_hex()), Some(p));
+        assert_eq!(Proof::from_base64url(&p.to_base64url()), Some(p));
+        assert_eq!(Proof::from_json(&p.to_json()), Some(p));
+        for text in [p.to_hex(), p.to_base64url(), p.to_json(), format!(" {}\n", p.to_hex())].iter() {
+            assert_eq!(decode_proof(text), Some(p));
+        }
+
+        assert_eq!(Proof::from_bytes(&p.to_bytes()[1..]), None);
+        assert_eq!(Proof::from_hex("1 2 3"), None);
+        // One hex form per proof: no signs, capitals, leading zeros or
+        // extra spaces
+        let hex = p.to_hex();
+        for bad in [hex.replacen("2f3a1", "+2f3a1", 1), hex.replacen("2f3a1", "2F3A1", 1),
+                    hex.replacen("2f3a1", "02f3a1", 1), hex.replacen(' ', "  ", 1)].iter() {
+            assert_eq!(Proof::from_hex(bad), None);
+        }
+        assert_eq!(Proof::from_json(&p.to_json().replacen(",193441,", ",+193441,", 1)), None);
+        assert_eq!(Proof::from_json("{\"nonces\":[1,2,3]}"), None);
+        assert_eq!(decode_proof("{\"nonces\":[1,2"), None);
+    }
+
+    #[test]
+    fn challenges_round_trip() {
+        let c = Challenge {
+            header: String::from("Zx9\"q"),
+            easipct: 70,
+            target: Target::from_expected_attempts(3),
+            params: Params::new(Variant::Cuckaroo, 19),
+            keying: KeyingScheme::Blake2b256,
+        };
+        assert_eq!(
+            c.to_json(),
+            "{\"header\":\"Zx9\\\"q\",\"easiness\":70,\"target\":\"6148914691236517204\",\"params\":\"cuckaroo19\",\"keying\":\"blake2b256\"}"
+        );
+        assert_eq!(c.to_bytes().len(), 5 + 17);
+        assert_eq!(Challenge::from_bytes(&c.to_bytes()), Some(c.clone()));
+        assert_eq!(Challenge::from_hex(&c.to_hex()), Some(c.clone()));
+        assert_eq!(Challenge::from_base64url(&c.to_base64url()), Some(c.clone()));
+        assert_eq!(Challenge::from_json(&c.to_json()), Some(c.clone()));
+
+        // The gateway's 401 body has more fields, and keying may be missing
+        let body = "{ \"header\": \"abc\", \"easiness\": 50, \"target\": \"18446744073709551615\", \"params\": \"cuckoo16\", \
+                    \"expires_in\": 300, \"error\": \"expired\" }";
+        let parsed = Challenge::from_json(body).unwrap();
+        assert_eq!(parsed.header, "abc");
+        assert_eq!(parsed.target, Target::MAX);
+        assert_eq!(parsed.keying, KeyingScheme::Legacy);
+
+        let mut bytes = c.to_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] = 9;
+        assert_eq!(Challenge::from_bytes(&bytes), None);
+        bytes[last - 1] = 99;
+        assert_eq!(Challenge::from_bytes(&bytes), None);
+        assert_eq!(Challenge::from_json("{\"header\":\"abc\"}"), None);
+        let long = Challenge {
+            header: "x".repeat(0x10000),
+            ..c.clone()
+        };
+        assert_eq!(Challenge::from_json(&long.to_json()), None);
+        assert_eq!(Challenge::from_hex(&c.to_hex().to_uppercase()), None);
+    }
+
+    #[test]
+    fn base64url_is_canonical() {
+        assert_eq!(base64url(b""), "");
+        assert_eq!(base64url(b"f"), "Zg");
+        assert_eq!(base64url(b"fo"), "Zm8");
+        assert_eq!(base64url(b"foo"), "Zm9v");
+        assert_eq!(base64url(&[0xfb, 0xff]), "-_8");
+        for len in 0..8 {
+            let bytes: Vec<u8> = (0..len).map(|i| (i * 37 + 200) as u8).collect();
+            assert_eq!(unbase64url(&base64url(&bytes)), Some(bytes));
+        }
+        assert_eq!(unbase64url("Zh"), None);
+        assert_eq!(unbase64url("Zm8="), None);
+        assert_eq!(unbase64url("Z"), None);
+    }
+}
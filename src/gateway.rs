@@ -0,0 +1,457 @@
+use log::{self, LevelFilter, Log};
+use std::io;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+
+use access_log::AccessLog;
+use handler::{Handler, ProxyHandler};
+use http_server::{
+    self, AssetSource, GatewayState, PuzzlePolicy, ServerHandle, ServerOptions, VerifyEvent,
+};
+use metrics::Metrics;
+use verifier::BatchVerifier;
+
+// Sets up a gateway for embedding in another program:
+//
+//     let handle = GatewayBuilder::new()
+//         .listen("127.0.0.1:8080")
+//         .upstream("127.0.0.1:3000")
+//         .build()?
+//         .spawn()?;
+//     ...
+//     handle.shutdown()?;
+pub struct GatewayBuilder {
+    addrs: Vec<String>,
+    listeners: Vec<TcpListener>,
+    inherit: bool,
+    assets: AssetSource,
+    policy: PuzzlePolicy,
+    attack_policy: Option<PuzzlePolicy>,
+    logger: Option<&'static dyn Log>,
+    log_level: Option<LevelFilter>,
+    options: ServerOptions,
+}
+
+impl GatewayBuilder {
+    pub fn new() -> GatewayBuilder {
+        GatewayBuilder {
+            addrs: Vec::new(),
+            listeners: Vec::new(),
+            inherit: false,
+            assets: AssetSource::default(),
+            policy: PuzzlePolicy::default(),
+            attack_policy: None,
+            logger: None,
+            log_level: None,
+            options: ServerOptions::default(),
+        }
+    }
+
+    // Binds `addr` on build. Can be called more than once.
+    pub fn listen(mut self, addr: &str) -> GatewayBuilder {
+        self.addrs.push(addr.to_string());
+        self
+    }
+
+    // Serves on a listener the caller already bound
+    pub fn listener(mut self, listener: TcpListener) -> GatewayBuilder {
+        self.listeners.push(listener);
+        self
+    }
+
+    // Serves on a socket passed down under LISTEN_FDS instead of binding
+    // the listen addresses, if there is one
+    pub fn inherit_listener(mut self) -> GatewayBuilder {
+        self.inherit = true;
+        self
+    }
+
+    pub fn assets(mut self, assets: AssetSource) -> GatewayBuilder {
+        self.assets = assets;
+        self
+    }
+
+    pub fn policy(mut self, policy: PuzzlePolicy) -> GatewayBuilder {
+        self.policy = policy;
+        self
+    }
+
+    pub fn attack_policy(mut self, policy: PuzzlePolicy) -> GatewayBuilder {
+        self.attack_policy = Some(policy);
+        self
+    }
+
+    pub fn challenge_ttl(mut self, ttl: Duration) -> GatewayBuilder {
+        self.options.challenge_ttl = ttl;
+        self
+    }
+
+    // Forwards requests that get through to `addr`. Shorthand for
+    // handler(ProxyHandler::new(addr)).
+    pub fn upstream(self, addr: &str) -> GatewayBuilder {
+        self.handler(ProxyHandler::new(addr))
+    }
+
+    // Serves requests that get through in-process
+    pub fn handler<H: Handler + 'static>(mut self, handler: H) -> GatewayBuilder {
+        self.options.handler = Some(Arc::new(handler));
+        self
+    }
+
+    // Unverified requests under `prefix` get a JSON challenge rather than
+    // the interstitial page. Clients sending Accept: application/json get
+    // one anywhere.
+    pub fn api_path(mut self, prefix: &str) -> GatewayBuilder {
+        self.options.api_paths.push(prefix.to_string());
+        self
+    }
+
+    // Hands verified clients a token that lets them skip the challenge for
+    // `ttl`. Off by default, so every request needs a fresh proof.
+    pub fn clearance_ttl(mut self, ttl: Duration) -> GatewayBuilder {
+        self.options.clearance_ttl = Some(ttl);
+        self
+    }
+
+    // Called with every submitted solution, valid or not
+    pub fn on_verify<F: Fn(&VerifyEvent) + Send + Sync + 'static>(mut self, f: F) -> GatewayBuilder {
+        self.options.verify_hook = Some(Arc::new(f));
+        self
+    }
+
+    // Threads checking submitted proofs, one per core by default
+    pub fn verify_threads(mut self, threads: usize) -> GatewayBuilder {
+        self.options.verifier = Arc::new(BatchVerifier::new().threads(threads));
+        self
+    }
+
+    // One line per request, in whichever LogFormat `log` was made with.
+    // Diagnostics go through the log facade, see logger and log_level.
+    pub fn access_log(mut self, log: AccessLog) -> GatewayBuilder {
+        self.options.access_log = Some(Arc::new(log));
+        self
+    }
+
+    // Shares a registry with the caller, e.g. to export it elsewhere
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> GatewayBuilder {
+        self.options.metrics = metrics;
+        self
+    }
+
+    // Installs `logger` for the gateway's diagnostics on build, at Info
+    // unless log_level says otherwise. The log facade has one logger per
+    // process, so build fails if the application already installed one;
+    // leave this unset to share the application's.
+    pub fn logger(mut self, logger: &'static dyn Log) -> GatewayBuilder {
+        self.logger = Some(logger);
+        self
+    }
+
+    // Caps what reaches the logger, process wide like the logger itself
+    pub fn log_level(mut self, level: LevelFilter) -> GatewayBuilder {
+        self.log_level = Some(level);
+        self
+    }
+
+    pub fn admin(mut self, addr: &str, token: Option<String>) -> GatewayBuilder {
+        self.options.admin_addr = Some(addr.to_string());
+        self.options.admin_token = token;
+        self
+    }
+
+    pub fn drain_timeout(mut self, timeout: Duration) -> GatewayBuilder {
+        self.options.drain_timeout = timeout;
+        self
+    }
+
+    pub fn state_path(mut self, path: &str) -> GatewayBuilder {
+        self.options.state_path = Some(path.to_string());
+        self
+    }
+
+    // Binds every listener. Nothing is served until spawn or run.
+    pub fn build(self) -> io::Result<Gateway> {
+        if let Some(logger) = self.logger {
+            log::set_logger(logger).map_err(|_| {
+                io::Error::new(io::ErrorKind::AlreadyExists, "a logger is already installed")
+            })?;
+            log::set_max_level(self.log_level.unwrap_or(LevelFilter::Info));
+        } else if let Some(level) = self.log_level {
+            log::set_max_level(level);
+        }
+
+        let mut listeners = self.listeners;
+        let inherited = if self.inherit {
+            http_server::inherited_listener()
+        } else {
+            None
+        };
+        match inherited {
+            Some(listener) => listeners.push(listener),
+            None => for addr in self.addrs.iter() {
+                listeners.push(TcpListener::bind(addr.as_str())?);
+            },
+        }
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "gateway has no listeners",
+            ));
+        }
+
+        let state = GatewayState::with_assets(self.assets);
+        state.set_policy(self.policy);
+        if let Some(attack_policy) = self.attack_policy {
+            state.set_attack_policy(attack_policy);
+        }
+        Ok(Gateway {
+            listeners,
+            state,
+            options: self.options,
+        })
+    }
+}
+
+impl Default for GatewayBuilder {
+    fn default() -> GatewayBuilder {
+        GatewayBuilder::new()
+    }
+}
+
+pub struct Gateway {
+    listeners: Vec<TcpListener>,
+    state: GatewayState,
+    options: ServerOptions,
+}
+
+impl Gateway {
+    // Serves on background threads until the handle is shut down
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        http_server::spawn_gateway(self.listeners, self.state, self.options)
+    }
+
+    // Serves on this thread until SIGTERM or SIGINT
+    pub fn run(self) -> io::Result<()> {
+        self.spawn()?.shutdown_on_signal()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cuckoo::PROOFSIZE;
+    use encoding::Encoding;
+    use gateway::GatewayBuilder;
+    use handler::{Request, Response};
+    use http_server::AssetSource;
+    use log::{self, LevelFilter, Log, Metadata, Record};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // Keeps every message, for the test to look through
+    struct Capture(Mutex<Vec<String>>);
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push(format!("{}", record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+    #[test]
+    fn installs_the_logger() {
+        let handle = GatewayBuilder::new()
+            .listen("127.0.0.1:0")
+            .assets(assets())
+            .logger(&CAPTURE)
+            .log_level(LevelFilter::Debug)
+            .build()
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let listening = format!("listening addr={}", handle.local_addr());
+        handle.shutdown().unwrap();
+        assert_eq!(log::max_level(), LevelFilter::Debug);
+        assert!(CAPTURE.0.lock().unwrap().contains(&listening));
+
+        // There's only one logger per process
+        assert!(GatewayBuilder::new().listen("127.0.0.1:0").logger(&CAPTURE).build().is_err());
+    }
+
+    fn assets() -> AssetSource {
+        AssetSource::Memory {
+            index: b"<p>HEADER|EASINESS|TARGET|PARAMS|KEYING</p>".to_vec(),
+            js: String::new(),
+            wasm: Vec::new(),
+        }
+    }
+
+    fn send(addr: SocketAddr, request: &[u8]) -> String {
+        let mut s = TcpStream::connect(addr).unwrap();
+        s.write_all(request).unwrap();
+        let mut response = Vec::new();
+        s.read_to_end(&mut response).unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[test]
+    fn needs_a_listener() {
+        assert!(GatewayBuilder::new().build().is_err());
+    }
+
+    #[test]
+    fn issues_challenges_and_reports_verification() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let handle = GatewayBuilder::new()
+            .listen("127.0.0.1:0")
+            .assets(assets())
+            .on_verify(move |e| {
+                seen.lock()
+                    .unwrap()
+                    .push((e.challenge.to_string(), e.rejected))
+            })
+            .build()
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let addr = handle.local_addr();
+
+        let page = send(addr, b"GET / HTTP/1.1\r\n\r\n");
+        let body = &page[page.find("<p>").unwrap() + 3..page.find("</p>").unwrap()];
+        let fields: Vec<&str> = body.split('|').collect();
+        assert_eq!(&fields[1..], &["70", "18446744073709551615", "cuckoo22", "legacy"]);
+        assert_eq!(handle.state().pending_challenges(), 1);
+
+        let zeros = vec!["0"; 42].join(" ");
+        let request = format!(
+            "GET / HTTP/1.1\r\nX-Cuckoo-Header: {}\r\nX-Cuckoo-Solution: {}\r\n\r\n",
+            fields[0], zeros
+        );
+        send(addr, request.as_bytes());
+        handle.shutdown().unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0], (fields[0].to_string(), Some("invalid_proof")));
+    }
+
+    #[test]
+    fn forwards_allowed_requests_upstream() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut s, _) = upstream.accept().unwrap();
+            let mut request = [0; 1024];
+            let n = s.read(&mut request).unwrap();
+            assert_eq!(&request[..n], &b"GET /api HTTP/1.1\r\nConnection: close\r\n\r\n"[..]);
+            s.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi")
+                .unwrap();
+        });
+
+        let handle = GatewayBuilder::new()
+            .listen("127.0.0.1:0")
+            .assets(assets())
+            .upstream(&upstream_addr.to_string())
+            .build()
+            .unwrap()
+            .spawn()
+            .unwrap();
+        handle.state().allow("127.0.0.1".parse().unwrap());
+
+        let response = send(handle.local_addr(), b"GET /api HTTP/1.1\r\n\r\n");
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi");
+        handle.shutdown().unwrap();
+    }
+
+    fn header<'a>(response: &'a str, name: &str) -> &'a str {
+        let start = response.find(name).unwrap() + name.len() + 2;
+        &response[start..start + response[start..].find("\r\n").unwrap()]
+    }
+
+    #[test]
+    fn challenges_api_clients_with_json() {
+        let handle = GatewayBuilder::new()
+            .listen("127.0.0.1:0")
+            .assets(assets())
+            .api_path("/api/")
+            .verify_threads(1)
+            .build()
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let addr = handle.local_addr();
+
+        let response = send(addr, b"GET /api/x HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        let challenge = header(&response, "X-Cuckoo-Header").to_string();
+        assert_eq!(header(&response, "X-Cuckoo-Easiness"), "70");
+        assert_eq!(header(&response, "X-Cuckoo-Target"), "18446744073709551615");
+        assert_eq!(header(&response, "X-Cuckoo-Params"), "cuckoo22");
+        assert!(response.ends_with(&format!(
+            "\r\n\r\n{{\"header\":\"{}\",\"easiness\":70,\"target\":\"18446744073709551615\",\
+             \"params\":\"cuckoo22\",\"keying\":\"legacy\",\"expires_in\":300}}",
+            challenge
+        )));
+
+        let response = send(addr, b"GET / HTTP/1.1\r\nAccept: application/json\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        let response = send(addr, b"GET / HTTP/1.1\r\nAccept: text/html,application/json\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // A bad proof gets a new challenge and the reason
+        let zeros = vec!["0"; 42].join(" ");
+        let request = format!(
+            "GET /api/x HTTP/1.1\r\nX-Cuckoo-Header: {}\r\nX-Cuckoo-Solution: {}\r\n\r\n",
+            challenge, zeros
+        );
+        let response = send(addr, request.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(header(&response, "X-Cuckoo-Header") != challenge);
+        assert!(response.ends_with(",\"error\":\"invalid_proof\"}"));
+
+        // Proofs in base64url are read too, anything else is malformed
+        let zeros = [0; PROOFSIZE].to_base64url();
+        let mut response = response;
+        for &(solution, error) in [(zeros.as_str(), "invalid_proof"), ("0 1 2", "malformed_solution")].iter() {
+            let request = format!(
+                "GET /api/x HTTP/1.1\r\nX-Cuckoo-Header: {}\r\nX-Cuckoo-Solution: {}\r\n\r\n",
+                header(&response, "X-Cuckoo-Header"),
+                solution
+            );
+            response = send(addr, request.as_bytes());
+            assert!(response.ends_with(&format!(",\"error\":\"{}\"}}", error)));
+        }
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    fn serves_allowed_requests_in_process() {
+        let handle = GatewayBuilder::new()
+            .listen("127.0.0.1:0")
+            .assets(assets())
+            .handler(|r: &Request| {
+                let body = format!("{} {} {}", r.method, r.path(), r.header("host").unwrap());
+                Ok(Response::new(200, "text/plain", body.into_bytes()))
+            })
+            .build()
+            .unwrap()
+            .spawn()
+            .unwrap();
+        handle.state().allow("127.0.0.1".parse().unwrap());
+
+        let response = send(handle.local_addr(), b"GET /x?y HTTP/1.1\r\nHost: example\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nGET /x example"));
+        handle.shutdown().unwrap();
+    }
+}
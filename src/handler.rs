@@ -0,0 +1,506 @@
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Component, Path, PathBuf};
+use std::str;
+use std::time::Duration;
+
+const UPSTREAM_TIMEOUT_SECS: u64 = 30;
+// Largest upstream response, head and body, the proxy will buffer
+const MAX_UPSTREAM_RESPONSE: usize = 16 << 20;
+
+// A request that got past the gateway, as read off the client connection
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub client: String,
+    pub method: String,
+    pub url: String,
+    // Request line, headers and body exactly as the client sent them
+    pub raw: Vec<u8>,
+}
+
+impl Request {
+    fn head_len(&self) -> usize {
+        self.raw
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map_or(self.raw.len(), |i| i + 4)
+    }
+
+    // The first header called `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<String> {
+        header_value(&self.raw[..self.head_len()], name)
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.raw[self.head_len()..]
+    }
+
+    // The url without its query string
+    pub fn path(&self) -> &str {
+        match self.url.find('?') {
+            Some(i) => &self.url[..i],
+            None => &self.url,
+        }
+    }
+}
+
+// The first header called `name` in a request or response head, ignoring
+// case
+fn header_value(head: &[u8], name: &str) -> Option<String> {
+    let head = String::from_utf8_lossy(head).into_owned();
+    head.split("\r\n").skip(1).filter_map(|line| {
+        let i = line.find(':')?;
+        if line[..i].trim().eq_ignore_ascii_case(name) {
+            Some(line[i + 1..].trim().to_string())
+        } else {
+            None
+        }
+    }).next()
+}
+
+// A complete HTTP/1.1 response, ready to be written to the client
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    bytes: Vec<u8>,
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, mut body: Vec<u8>) -> Response {
+        let mut bytes = format!(
+            "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nContent-Type: {}\r\nConnection: close\r\n\r\n",
+            status,
+            reason(status),
+            body.len(),
+            content_type
+        ).into_bytes();
+        bytes.append(&mut body);
+        Response { bytes }
+    }
+
+    pub fn empty(status: u16) -> Response {
+        Response {
+            bytes: format!(
+                "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status,
+                reason(status)
+            ).into_bytes(),
+        }
+    }
+
+    // Wraps a response that is already encoded, e.g. one read from an
+    // upstream server
+    pub fn from_bytes(bytes: Vec<u8>) -> Response {
+        Response { bytes }
+    }
+
+    // The status code, if this looks like an HTTP/1.x response
+    pub fn status(&self) -> Option<u16> {
+        if self.bytes.len() < 12 || !self.bytes.starts_with(b"HTTP/1.") {
+            return None;
+        }
+        str::from_utf8(&self.bytes[9..12]).ok()?.parse::<u16>().ok()
+    }
+
+    // Adds a header right after the status line
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        let at = match self.bytes.windows(2).position(|w| w == b"\r\n") {
+            Some(i) => i + 2,
+            None => return self,
+        };
+        let header = format!("{}: {}\r\n", name, value).into_bytes();
+        self.bytes.splice(at..at, header);
+        self
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+// Serves requests that were verified, allowed or don't need a challenge.
+// An error is answered with a 502.
+pub trait Handler: Send + Sync {
+    fn handle(&self, request: &Request) -> io::Result<Response>;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> io::Result<Response> + Send + Sync,
+{
+    fn handle(&self, request: &Request) -> io::Result<Response> {
+        self(request)
+    }
+}
+
+// Sends the request to an upstream server and returns its response. The
+// upstream is asked to close the connection after it, and the challenge
+// headers meant for the gateway aren't passed on.
+pub struct ProxyHandler {
+    upstream: String,
+    timeout: Duration,
+    max_response: usize,
+}
+
+impl ProxyHandler {
+    pub fn new(upstream: &str) -> ProxyHandler {
+        ProxyHandler {
+            upstream: upstream.to_string(),
+            timeout: Duration::new(UPSTREAM_TIMEOUT_SECS, 0),
+            max_response: MAX_UPSTREAM_RESPONSE,
+        }
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> ProxyHandler {
+        self.timeout = timeout;
+        self
+    }
+
+    // Larger responses are dropped and answered with a 502
+    pub fn max_response(mut self, bytes: usize) -> ProxyHandler {
+        self.max_response = bytes;
+        self
+    }
+}
+
+// The request as the upstream should see it: hop-by-hop and X-Cuckoo-*
+// headers dropped, and Connection: close
+fn upstream_request(request: &Request) -> Vec<u8> {
+    let head_len = request.head_len();
+    let head = String::from_utf8_lossy(&request.raw[..head_len]).into_owned();
+    let mut forwarded = String::new();
+    for (i, line) in head.split("\r\n").enumerate() {
+        let name = line.split(':').next().unwrap_or("").trim().to_ascii_lowercase();
+        if line.is_empty()
+            || i > 0 && (name == "connection" || name == "keep-alive" || name.starts_with("x-cuckoo-"))
+        {
+            continue;
+        }
+        forwarded.push_str(line);
+        forwarded.push_str("\r\n");
+    }
+    forwarded.push_str("Connection: close\r\n\r\n");
+    let mut bytes = forwarded.into_bytes();
+    bytes.extend_from_slice(request.body());
+    bytes
+}
+
+// Reads more of the response into `buf`. False at end of stream.
+fn fill<R: Read>(r: &mut R, buf: &mut Vec<u8>, max: usize) -> io::Result<bool> {
+    let mut chunk = [0; 8192];
+    let n = r.read(&mut chunk)?;
+    if buf.len() + n > max {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "upstream response too large"));
+    }
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(n > 0)
+}
+
+// Index just past the next CRLF at or after `from`, reading as needed
+fn line_end<R: Read>(r: &mut R, buf: &mut Vec<u8>, from: usize, max: usize) -> io::Result<usize> {
+    loop {
+        if let Some(i) = buf[from..].windows(2).position(|w| w == b"\r\n") {
+            return Ok(from + i + 2);
+        }
+        if !fill(r, buf, max)? {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream response cut short"));
+        }
+    }
+}
+
+// One response, framed by its Content-Length or chunked encoding so a
+// keep-alive upstream doesn't have to close the connection first. Responses
+// with neither run to the end of the stream.
+fn read_response<R: Read>(r: &mut R, head_request: bool, max: usize) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let head_len = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        if !fill(r, &mut buf, max)? {
+            return Ok(buf);
+        }
+    };
+    let status = Response::from_bytes(buf[..head_len].to_vec()).status().unwrap_or(0);
+    let chunked = header_value(&buf[..head_len], "Transfer-Encoding")
+        .is_some_and(|te| te.to_ascii_lowercase().ends_with("chunked"));
+    let length = header_value(&buf[..head_len], "Content-Length").and_then(|l| l.parse::<usize>().ok());
+
+    let end = if head_request || status / 100 == 1 || status == 204 || status == 304 {
+        head_len
+    } else if chunked {
+        let mut at = head_len;
+        loop {
+            let size_end = line_end(r, &mut buf, at, max)?;
+            let size = String::from_utf8_lossy(&buf[at..size_end - 2]).into_owned();
+            let size = usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad chunk size from upstream"))?;
+            at = size_end;
+            if size == 0 {
+                break;
+            }
+            if size > max {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "upstream response too large"));
+            }
+            while buf.len() < at + size + 2 {
+                if !fill(r, &mut buf, max)? {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream response cut short"));
+                }
+            }
+            at += size + 2;
+        }
+        // Trailers, up to an empty line
+        loop {
+            let next = line_end(r, &mut buf, at, max)?;
+            let empty = next == at + 2;
+            at = next;
+            if empty {
+                break at;
+            }
+        }
+    } else if let Some(length) = length {
+        if length > max - head_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "upstream response too large"));
+        }
+        while buf.len() < head_len + length {
+            if !fill(r, &mut buf, max)? {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream response cut short"));
+            }
+        }
+        head_len + length
+    } else {
+        while fill(r, &mut buf, max)? {}
+        buf.len()
+    };
+    buf.truncate(end);
+    Ok(buf)
+}
+
+impl Handler for ProxyHandler {
+    fn handle(&self, request: &Request) -> io::Result<Response> {
+        let mut s = TcpStream::connect(self.upstream.as_str())?;
+        s.set_read_timeout(Some(self.timeout))?;
+        s.set_write_timeout(Some(self.timeout))?;
+        s.write_all(&upstream_request(request))?;
+        let response = read_response(&mut s, request.method == "HEAD", self.max_response)?;
+        Ok(Response::from_bytes(response))
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("html") | Some("htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js") => "application/javascript",
+        Some("json") => "application/json",
+        Some("wasm") => "application/wasm",
+        Some("txt") => "text/plain",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+// Serves GET and HEAD out of a directory. Paths ending in / get the
+// directory's index.html.
+pub struct StaticFileHandler {
+    root: PathBuf,
+}
+
+impl StaticFileHandler {
+    pub fn new<P: Into<PathBuf>>(root: P) -> StaticFileHandler {
+        StaticFileHandler { root: root.into() }
+    }
+
+    // None if the url tries to leave the root
+    fn resolve(&self, url_path: &str) -> Option<PathBuf> {
+        let relative = Path::new(url_path.trim_start_matches('/'));
+        let mut path = self.root.clone();
+        for component in relative.components() {
+            match component {
+                Component::Normal(c) => path.push(c),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        if url_path.ends_with('/') || path.is_dir() {
+            path.push("index.html");
+        }
+        Some(path)
+    }
+}
+
+impl Handler for StaticFileHandler {
+    fn handle(&self, request: &Request) -> io::Result<Response> {
+        let head = request.method == "HEAD";
+        if request.method != "GET" && !head {
+            return Ok(Response::empty(405));
+        }
+        let path = match self.resolve(request.path()) {
+            Some(path) => path,
+            None => return Ok(Response::empty(404)),
+        };
+        let body = match fs::read(&path) {
+            Ok(body) => body,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Response::empty(404))
+            }
+            Err(e) => return Err(e),
+        };
+        let mut response = Response::new(200, content_type(&path), body);
+        if head {
+            let end = response.bytes.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+            response.bytes.truncate(end);
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use handler::{Handler, ProxyHandler, Request, Response, StaticFileHandler};
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::process;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn request(method: &str, url: &str) -> Request {
+        Request {
+            client: String::from("127.0.0.1"),
+            method: method.to_string(),
+            url: url.to_string(),
+            raw: format!("{} {} HTTP/1.1\r\nHost: x\r\nX-Thing:  a b \r\n\r\nbody", method, url)
+                .into_bytes(),
+        }
+    }
+
+    #[test]
+    fn parses_requests_and_responses() {
+        let r = request("GET", "/a/b?c=d");
+        assert_eq!(r.header("x-thing"), Some(String::from("a b")));
+        assert_eq!(r.header("Missing"), None);
+        assert_eq!(r.body(), b"body");
+        assert_eq!(r.path(), "/a/b");
+
+        let response = Response::new(404, "text/plain", b"gone".to_vec());
+        assert_eq!(
+            response.as_bytes(),
+            &b"HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\ngone"[..]
+        );
+        assert_eq!(response.status(), Some(404));
+        assert!(response
+            .with_header("X-A", "b")
+            .as_bytes()
+            .starts_with(b"HTTP/1.1 404 Not Found\r\nX-A: b\r\nContent-Length: 4\r\n"));
+        assert_eq!(Response::from_bytes(b"garbage".to_vec()).status(), None);
+    }
+
+    #[test]
+    fn serves_static_files() {
+        let root = env::temp_dir().join(format!("cuckoo-static-{}", process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/index.html"), "<p>hi</p>").unwrap();
+        fs::write(root.join("app.js"), "1;").unwrap();
+        let files = StaticFileHandler::new(root.clone());
+
+        let r = files.handle(&request("GET", "/sub/")).unwrap();
+        assert_eq!(r.status(), Some(200));
+        assert!(r.as_bytes().ends_with(b"Content-Type: text/html\r\nConnection: close\r\n\r\n<p>hi</p>"));
+        let r = files.handle(&request("GET", "/sub")).unwrap();
+        assert!(r.as_bytes().ends_with(b"<p>hi</p>"));
+        let r = files.handle(&request("HEAD", "/app.js?v=2")).unwrap();
+        assert!(r.as_bytes().ends_with(b"Content-Length: 2\r\nContent-Type: application/javascript\r\nConnection: close\r\n\r\n"));
+
+        assert_eq!(files.handle(&request("GET", "/nope")).unwrap().status(), Some(404));
+        assert_eq!(files.handle(&request("GET", "/../etc/passwd")).unwrap().status(), Some(404));
+        assert_eq!(files.handle(&request("POST", "/app.js")).unwrap().status(), Some(405));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    // An upstream that answers one request with `response` and then keeps
+    // the connection open. Sends back the request it got.
+    fn keep_alive_upstream(response: &'static [u8]) -> (String, mpsc::Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = s.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            s.write_all(response).unwrap();
+            tx.send(request).unwrap();
+            thread::sleep(Duration::from_secs(5));
+        });
+        (addr, rx)
+    }
+
+    #[test]
+    fn proxies_framed_responses() {
+        let mut r = request("GET", "/api");
+        r.raw = b"GET /api HTTP/1.1\r\nHost: x\r\nConnection: keep-alive\r\nX-Cuckoo-Header: ab\r\nx-cuckoo-solution: 1 2\r\n\r\n".to_vec();
+
+        let (addr, sent) = keep_alive_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhiEXTRA");
+        let proxy = ProxyHandler::new(&addr).timeout(Duration::from_secs(2));
+        let response = proxy.handle(&r).unwrap();
+        assert_eq!(response.as_bytes(), &b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi"[..]);
+        assert_eq!(
+            sent.recv().unwrap(),
+            b"GET /api HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n".to_vec()
+        );
+
+        let chunked: &'static [u8] =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2;x=y\r\nhi\r\n3\r\n th\r\n0\r\nA: b\r\n\r\n";
+        let (addr, _sent) = keep_alive_upstream(chunked);
+        let response = ProxyHandler::new(&addr).timeout(Duration::from_secs(2)).handle(&r).unwrap();
+        assert_eq!(response.as_bytes(), chunked);
+
+        let (addr, _sent) = keep_alive_upstream(b"HTTP/1.1 204 No Content\r\n\r\n");
+        let response = ProxyHandler::new(&addr).timeout(Duration::from_secs(2)).handle(&r).unwrap();
+        assert_eq!(response.status(), Some(204));
+
+        // Over the cap, whether it says so up front or not
+        let (addr, _sent) = keep_alive_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n");
+        assert!(ProxyHandler::new(&addr).max_response(64).handle(&r).is_err());
+        let (addr, _sent) = keep_alive_upstream(chunked);
+        assert!(ProxyHandler::new(&addr).max_response(40).handle(&r).is_err());
+    }
+}
@@ -1,17 +1,30 @@
-use rand::{thread_rng, AsciiGenerator, Rng, ThreadRng};
-use std::collections::HashMap;
+use rand::Rng;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io;
 use std::io::{Read, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::process::{Child, Command};
 use std::str;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::u64;
 use std::vec::Vec;
 
+use access_log::{json_string, AccessEntry, AccessLog, Outcome};
+use admin;
+use csprng::{Csprng, SharedCsprng};
 use cuckoo;
+use difficulty::Target;
+use encoding::{decode_proof, Challenge, Encoding};
+use handler::{Handler, Request, Response};
+use metrics::Metrics;
+use verifier::BatchVerifier;
 
 const BUF_SIZE: usize = 8192;
 const CONTENT_LENGTH: &[u8] = b"Content-Length:";
@@ -19,7 +32,18 @@ const HEADER_END: &[u8] = b"\n\r\n";
 const HEADER_LENGTH: usize = 32;
 const RNG_BUF_SIZE: usize = 8;
 const EASIPCT: i32 = 70;
-const DIFFICULTY: f64 = 99.9;
+// Proofs a client should expect to find per accepted one
+const EXPECTED_ATTEMPTS: u64 = 1;
+// Used instead of EXPECTED_ATTEMPTS while under attack
+const ATTACK_EXPECTED_ATTEMPTS: u64 = 4;
+// How long an issued challenge stays solvable
+const CHALLENGE_TTL_SECS: u64 = 300;
+const ADMIN_TOKEN_BYTES: usize = 16;
+const DRAIN_TIMEOUT_SECS: u64 = 10;
+const SIGNAL_POLL_MS: u64 = 100;
+// First fd passed under the LISTEN_FDS protocol
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
 
 struct TCPRead {
     tcp_stream: TcpStream,
@@ -35,9 +59,10 @@ impl TCPRead {
         if self.buf_ptr >= self.end_of_read {
             match self.tcp_stream.read(&mut self.buf) {
                 Err(_) => {
-                    self.tcp_stream.shutdown(Shutdown::Both).unwrap();
+                    self.tcp_stream.shutdown(Shutdown::Both).ok();
                     return None;
                 }
+                Ok(0) => return None,
                 Ok(n) => {
                     self.buf_ptr = 0;
                     self.offset += self.end_of_read;
@@ -122,8 +147,9 @@ impl HTTPRead {
         Ok(())
     }
 
+    // The peer may already be gone, and then there's nothing left to close
     fn close(&mut self) {
-        self.tcp_read.tcp_stream.shutdown(Shutdown::Both).unwrap();
+        self.tcp_read.tcp_stream.shutdown(Shutdown::Both).ok();
     }
 
     fn read_until_body(&mut self, c: &u8) -> bool {
@@ -135,7 +161,7 @@ impl HTTPRead {
 
         if self.rest_of_http_header == HEADER_END.len() {
             self.rest_of_http_header = 0;
-            println!("Found body!");
+            trace!("header complete at={}", self.tcp_read.ptr());
             return true;
         } else {
             return false;
@@ -244,7 +270,7 @@ impl HTTPRead {
                     let cl = self.content_length.unwrap();
                     if self.read_until_body(&c) {
                         if cl == 0 {
-                            println!("Done!");
+                            trace!("request read len={}", result.len());
                             self.read_state = HTTPReadState::ReadingMethod;
                             break;
                         }
@@ -256,7 +282,7 @@ impl HTTPRead {
                 HTTPReadState::ReadingBody => {
                     //println!("end_ptr is {:?}, i is {:?}", i, self.end_ptr);
                     if i >= self.end_ptr - 1 {
-                        println!("Done!");
+                        trace!("request read len={}", result.len());
                         self.read_state = HTTPReadState::ReadingMethod;
                         break;
                     }
@@ -270,8 +296,8 @@ impl HTTPRead {
 
 enum VerifyStatus {
     Unverified,
-    Invalid,
-    Valid,
+    Invalid(Submission, &'static str),
+    Valid(Submission),
 }
 
 fn format_response_text(body: &String, content_type: &'static str) -> String {
@@ -353,44 +379,413 @@ fn efficient_replace(orig_text: &[u8], text_to_find: &[u8], replace_with: &[u8])
     return new_text;
 }
 
-#[derive(Hash, PartialEq, Eq)]
+#[derive(Clone, Copy, Hash, PartialEq, Eq)]
 enum StaticResource {
     WebMinerJS,
     WebMinerWasm,
     WebMinerHtml,
 }
 
+impl StaticResource {
+    fn name(&self) -> &'static str {
+        match *self {
+            StaticResource::WebMinerJS => "web_miner.js",
+            StaticResource::WebMinerWasm => "web_miner.wasm",
+            StaticResource::WebMinerHtml => "index.html",
+        }
+    }
+}
+
+type Assets = HashMap<StaticResource, Vec<u8>>;
+
+// Where the challenge page and the wasm miner come from. The page has
+// HEADER, EASINESS, TARGET, PARAMS and KEYING placeholders.
+#[derive(Clone, Debug)]
+pub enum AssetSource {
+    // Read on startup and on every reload
+    Files {
+        index: PathBuf,
+        js: PathBuf,
+        wasm: PathBuf,
+    },
+    Memory {
+        index: Vec<u8>,
+        js: String,
+        wasm: Vec<u8>,
+    },
+}
+
+impl Default for AssetSource {
+    fn default() -> AssetSource {
+        AssetSource::Files {
+            index: PathBuf::from("static/index.html"),
+            js: PathBuf::from("target/wasm32-unknown-unknown/release/web_miner.js"),
+            wasm: PathBuf::from("target/wasm32-unknown-unknown/release/web_miner.wasm"),
+        }
+    }
+}
+
+fn load_assets(source: &AssetSource) -> io::Result<Assets> {
+    let (index, js, wasm) = match *source {
+        AssetSource::Files {
+            ref index,
+            ref js,
+            ref wasm,
+        } => (fs::read(index)?, fs::read_to_string(js)?, fs::read(wasm)?),
+        AssetSource::Memory {
+            ref index,
+            ref js,
+            ref wasm,
+        } => (index.clone(), js.clone(), wasm.clone()),
+    };
+    let mut st = HashMap::new();
+    st.insert(StaticResource::WebMinerHtml, index);
+    st.insert(
+        StaticResource::WebMinerJS,
+        format_response_text(&js, "application/javascript")
+            .as_bytes()
+            .to_vec(),
+    );
+    st.insert(
+        StaticResource::WebMinerWasm,
+        format_response_binary(wasm, "application/wasm"),
+    );
+    Ok(st)
+}
+
 #[derive(Clone)]
 struct CuckooProblem {
     easipct: i32,
-    difficulty: f64,
+    target: Target,
+    keying: cuckoo::KeyingScheme,
+    params: cuckoo::Params,
+    issued: Instant,
+}
+
+impl CuckooProblem {
+    fn challenge(&self, header: &[u8]) -> Challenge {
+        Challenge {
+            header: String::from_utf8_lossy(header).into_owned(),
+            easipct: self.easipct,
+            target: self.target,
+            params: self.params,
+            keying: self.keying,
+        }
+    }
 }
 
 type RequestMap = HashMap<Vec<u8>, CuckooProblem>;
 
-struct HeaderGenerator<'a> {
-    u8_gen: AsciiGenerator<'a, ThreadRng>,
+// What newly issued challenges look like
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PuzzlePolicy {
+    pub easipct: i32,
+    pub target: Target,
+    pub keying: cuckoo::KeyingScheme,
+    pub params: cuckoo::Params,
+}
+
+impl Default for PuzzlePolicy {
+    fn default() -> PuzzlePolicy {
+        PuzzlePolicy {
+            easipct: EASIPCT,
+            target: Target::from_expected_attempts(EXPECTED_ATTEMPTS),
+            keying: cuckoo::KeyingScheme::Legacy,
+            params: cuckoo::Params::default(),
+        }
+    }
+}
+
+impl PuzzlePolicy {
+    fn problem(&self) -> CuckooProblem {
+        CuckooProblem {
+            easipct: self.easipct,
+            target: self.target,
+            keying: self.keying,
+            params: self.params,
+            issued: Instant::now(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChallengeInfo {
+    pub id: String,
+    pub params: cuckoo::Params,
+    pub age: Duration,
+}
+
+// Everything about a running gateway that can change while it serves
+pub struct GatewayState {
+    unsolved: Mutex<RequestMap>,
+    policy: RwLock<PuzzlePolicy>,
+    attack_policy: RwLock<PuzzlePolicy>,
+    under_attack: AtomicBool,
+    allow: RwLock<HashSet<IpAddr>>,
+    deny: RwLock<HashSet<IpAddr>>,
+    // Clearance token to when it stops being accepted
+    clearances: Mutex<HashMap<Vec<u8>, Instant>>,
+    asset_source: AssetSource,
+    assets: RwLock<Arc<Assets>>,
+}
+
+impl GatewayState {
+    // Starts with no assets; call reload_assets before serving
+    pub fn new() -> GatewayState {
+        GatewayState::with_assets(AssetSource::default())
+    }
+
+    pub fn with_assets(asset_source: AssetSource) -> GatewayState {
+        GatewayState {
+            unsolved: Mutex::new(HashMap::new()),
+            policy: RwLock::new(PuzzlePolicy::default()),
+            attack_policy: RwLock::new(PuzzlePolicy {
+                target: Target::from_expected_attempts(ATTACK_EXPECTED_ATTEMPTS),
+                ..PuzzlePolicy::default()
+            }),
+            under_attack: AtomicBool::new(false),
+            allow: RwLock::new(HashSet::new()),
+            deny: RwLock::new(HashSet::new()),
+            clearances: Mutex::new(HashMap::new()),
+            asset_source,
+            assets: RwLock::new(Arc::new(HashMap::new())),
+        }
+    }
+
+    pub fn policy(&self) -> PuzzlePolicy {
+        *self.policy.read().unwrap()
+    }
+
+    pub fn set_policy(&self, policy: PuzzlePolicy) {
+        *self.policy.write().unwrap() = policy;
+    }
+
+    pub fn attack_policy(&self) -> PuzzlePolicy {
+        *self.attack_policy.read().unwrap()
+    }
+
+    pub fn set_attack_policy(&self, policy: PuzzlePolicy) {
+        *self.attack_policy.write().unwrap() = policy;
+    }
+
+    pub fn under_attack(&self) -> bool {
+        self.under_attack.load(Ordering::Relaxed)
+    }
+
+    // While under attack, new challenges use the attack policy and the
+    // allow list is ignored
+    pub fn set_under_attack(&self, under_attack: bool) {
+        self.under_attack.store(under_attack, Ordering::Relaxed);
+    }
+
+    pub fn current_policy(&self) -> PuzzlePolicy {
+        if self.under_attack() {
+            self.attack_policy()
+        } else {
+            self.policy()
+        }
+    }
+
+    pub fn challenges(&self) -> Vec<ChallengeInfo> {
+        let unlocked = self.unsolved.lock().unwrap();
+        let mut challenges: Vec<ChallengeInfo> = unlocked
+            .iter()
+            .map(|(id, p)| ChallengeInfo {
+                id: String::from_utf8_lossy(id).into_owned(),
+                params: p.params,
+                age: p.issued.elapsed(),
+            })
+            .collect();
+        challenges.sort_by_key(|c| Reverse(c.age));
+        challenges
+    }
+
+    pub fn pending_challenges(&self) -> usize {
+        self.unsolved.lock().unwrap().len()
+    }
+
+    // Drops one outstanding challenge, or all of them. Returns how many went.
+    pub fn purge_challenges(&self, id: Option<&str>) -> usize {
+        let mut unlocked = self.unsolved.lock().unwrap();
+        match id {
+            Some(id) => unlocked.remove(id.as_bytes()).map_or(0, |_| 1),
+            None => {
+                let n = unlocked.len();
+                unlocked.clear();
+                n
+            }
+        }
+    }
+
+    pub fn allow(&self, ip: IpAddr) {
+        self.allow.write().unwrap().insert(ip);
+    }
+
+    pub fn remove_allow(&self, ip: &IpAddr) -> bool {
+        self.allow.write().unwrap().remove(ip)
+    }
+
+    pub fn allowed(&self) -> Vec<IpAddr> {
+        let mut ips: Vec<IpAddr> = self.allow.read().unwrap().iter().cloned().collect();
+        ips.sort();
+        ips
+    }
+
+    pub fn deny(&self, ip: IpAddr) {
+        self.deny.write().unwrap().insert(ip);
+    }
+
+    pub fn remove_deny(&self, ip: &IpAddr) -> bool {
+        self.deny.write().unwrap().remove(ip)
+    }
+
+    pub fn denied(&self) -> Vec<IpAddr> {
+        let mut ips: Vec<IpAddr> = self.deny.read().unwrap().iter().cloned().collect();
+        ips.sort();
+        ips
+    }
+
+    fn is_allowed(&self, ip: &IpAddr) -> bool {
+        !self.under_attack() && self.allow.read().unwrap().contains(ip)
+    }
+
+    fn is_denied(&self, ip: &IpAddr) -> bool {
+        self.deny.read().unwrap().contains(ip)
+    }
+
+    fn grant_clearance(&self, token: &[u8], ttl: Duration) {
+        let now = Instant::now();
+        let mut clearances = self.clearances.lock().unwrap();
+        clearances.retain(|_, expires| *expires > now);
+        clearances.insert(token.to_vec(), now + ttl);
+    }
+
+    // Like the allow list, clearances count for nothing while under attack
+    fn is_cleared(&self, token: &[u8]) -> bool {
+        if self.under_attack() {
+            return false;
+        }
+        match self.clearances.lock().unwrap().get(token) {
+            Some(expires) => *expires > Instant::now(),
+            None => false,
+        }
+    }
+
+    // Re-reads the page and miner from their source. The old copies stay in
+    // use if anything fails to load.
+    pub fn reload_assets(&self) -> io::Result<()> {
+        let assets = load_assets(&self.asset_source)?;
+        *self.assets.write().unwrap() = Arc::new(assets);
+        Ok(())
+    }
+
+    // One challenge per line: id, params, easipct, target, keying, age
+    // in milliseconds
+    pub fn save_challenges(&self, path: &str) -> io::Result<usize> {
+        let mut out = String::new();
+        let unlocked = self.unsolved.lock().unwrap();
+        for (id, p) in unlocked.iter() {
+            let age = p.issued.elapsed();
+            out.push_str(&format!(
+                "{} {} {} {} {} {}\n",
+                String::from_utf8_lossy(id),
+                p.params,
+                p.easipct,
+                p.target,
+                p.keying.name(),
+                age.as_secs() * 1000 + age.subsec_millis() as u64
+            ));
+        }
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, out)?;
+        fs::rename(&tmp, path)?;
+        Ok(unlocked.len())
+    }
+
+    // Adds the challenges saved by save_challenges, skipping any that have
+    // outlived `ttl` since
+    pub fn load_challenges(&self, path: &str, ttl: Duration) -> io::Result<usize> {
+        let saved = fs::read_to_string(path)?;
+        let now = Instant::now();
+        let mut loaded = 0;
+        let mut unlocked = self.unsolved.lock().unwrap();
+        for line in saved.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            if fields.len() != 6 {
+                continue;
+            }
+            let keying = match cuckoo::KeyingScheme::parse(fields[4]) {
+                Some(keying) => keying,
+                None => continue,
+            };
+            let parsed = (
+                cuckoo::Params::parse(fields[1]),
+                fields[2].parse::<i32>(),
+                // Older saves have a float percentage here
+                Target::parse(fields[3]).or_else(|| fields[3].parse::<f64>().ok().map(Target::from_percent)),
+                fields[5].parse::<u64>(),
+            );
+            if let (Some(params), Ok(easipct), Some(target), Ok(age_ms)) = parsed {
+                let age = Duration::from_millis(age_ms);
+                let issued = match now.checked_sub(age) {
+                    Some(issued) if age <= ttl => issued,
+                    _ => continue,
+                };
+                unlocked.insert(
+                    fields[0].as_bytes().to_vec(),
+                    CuckooProblem {
+                        easipct,
+                        target,
+                        keying,
+                        params,
+                        issued,
+                    },
+                );
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    fn assets(&self) -> Arc<Assets> {
+        self.assets.read().unwrap().clone()
+    }
+}
+
+impl Default for GatewayState {
+    fn default() -> GatewayState {
+        GatewayState::new()
+    }
+}
+
+struct HeaderGenerator {
+    rng: SharedCsprng,
     tmp: Vec<[u8; HEADER_LENGTH]>,
 }
 
-impl<'a> HeaderGenerator<'a> {
+impl HeaderGenerator {
+    fn new(rng: SharedCsprng) -> HeaderGenerator {
+        HeaderGenerator {
+            rng,
+            tmp: Vec::new(),
+        }
+    }
+
     fn regenerate(&mut self) {
         if self.tmp.len() == 0 {
+            let mut rng = self.rng.lock().unwrap();
             for _ in 0..RNG_BUF_SIZE {
-                let u = &mut self.u8_gen;
-                let a = u.take(HEADER_LENGTH).collect::<Vec<char>>();
                 let mut c: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
-                let a_bytes: Vec<u8> = a.into_iter()
-                    .map(|q| if q == '\r' { b'R' } else { q as u8 })
-                    .collect();
-                &c.clone_from_slice(&a_bytes);
+                for (b, q) in c.iter_mut().zip(rng.gen_ascii_chars()) {
+                    *b = q as u8;
+                }
                 self.tmp.push(c);
             }
         }
     }
 }
 
-impl<'a> Iterator for HeaderGenerator<'a> {
+impl Iterator for HeaderGenerator {
     type Item = [u8; HEADER_LENGTH];
 
     fn next(&mut self) -> Option<[u8; HEADER_LENGTH]> {
@@ -405,86 +800,339 @@ fn requires_cuckoo(_: &[u8]) -> bool {
     true
 }
 
-fn verified(unsolved_requests: Arc<Mutex<RequestMap>>, request: &[u8]) -> VerifyStatus {
+// Which challenge a request answered, and what we knew about it
+struct Submission {
+    challenge: String,
+    problem: Option<CuckooProblem>,
+    solve_latency: Option<Duration>,
+}
+
+// An untrusted header value for the logs: printable ASCII only, cut to the
+// length of a real challenge
+fn log_safe(bytes: &[u8]) -> String {
+    let mut out: String = bytes
+        .iter()
+        .take(HEADER_LENGTH)
+        .map(|&b| if b > b' ' && b < 0x7f { b as char } else { '?' })
+        .collect();
+    if bytes.len() > HEADER_LENGTH {
+        out.push_str("...");
+    }
+    out
+}
+
+fn verified(
+    state: &GatewayState,
+    request: &[u8],
+    client: &str,
+    options: &ServerOptions,
+) -> VerifyStatus {
     let res = efficient_parse_header(request, b"X-Cuckoo-Header: ");
     match res {
         Some(header_bytes) => {
             // Verify request here
+            let mut submission = Submission {
+                challenge: String::from_utf8_lossy(&header_bytes).into_owned(),
+                problem: None,
+                solve_latency: None,
+            };
 
             let p: CuckooProblem;
             {
-                let unlocked = unsolved_requests.lock().unwrap();
+                let unlocked = state.unsolved.lock().unwrap();
                 let p_raw: Option<&CuckooProblem> = unlocked.get(&header_bytes);
 
                 match p_raw {
                     None => {
-                        return VerifyStatus::Invalid;
+                        info!(
+                            "solution rejected client={} challenge={} reason=unknown_challenge",
+                            client,
+                            log_safe(&header_bytes)
+                        );
+                        return VerifyStatus::Invalid(submission, "unknown_challenge");
                     }
                     Some(p_unwrapped) => {
                         p = (*p_unwrapped).clone();
                     }
                 }
             }
+            submission.problem = Some(p.clone());
+            submission.solve_latency = Some(p.issued.elapsed());
 
-            let easiness: i32 = ((p.easipct as i64 * cuckoo::NNODES as i64) / 100) as i32;
-            let hash_difficulty: u64 = ((p.difficulty / 100.0) * u64::MAX as f64) as u64;
+            if p.issued.elapsed() > options.challenge_ttl {
+                let mut unlocked = state.unsolved.lock().unwrap();
+                if unlocked.remove(&header_bytes).is_some() {
+                    options.metrics.challenges_expired.inc();
+                }
+                options.metrics.pending_challenges.set(unlocked.len() as i64);
+                info!(
+                    "solution rejected client={} challenge={} reason=expired",
+                    client, submission.challenge
+                );
+                return VerifyStatus::Invalid(submission, "expired");
+            }
 
-            //println!("{:?}", str::from_utf8(&header_bytes).unwrap());
             let solution_raw = efficient_parse_header(request, b"X-Cuckoo-Solution: ");
-            let mut solution: cuckoo::Proof = [0; cuckoo::PROOFSIZE];
+            let solution: cuckoo::Proof;
 
             match solution_raw {
                 None => {
-                    return VerifyStatus::Invalid;
+                    info!(
+                        "solution rejected client={} challenge={} reason=missing_solution",
+                        client, submission.challenge
+                    );
+                    return VerifyStatus::Invalid(submission, "missing_solution");
                 }
                 Some(sol) => {
-                    // TODO: Figure out more efficient way to do this part
-                    let sol_str_raw = str::from_utf8(&sol);
-                    let sol_str: &str;
-                    match sol_str_raw {
-                        Err(_) => {
-                            return VerifyStatus::Invalid;
+                    // Hex as the miners send it, or base64url
+                    match str::from_utf8(&sol).ok().and_then(decode_proof) {
+                        Some(proof) => {
+                            solution = proof;
                         }
-                        Ok(st) => {
-                            sol_str = st;
+                        None => {
+                            info!(
+                                "solution rejected client={} challenge={} reason=malformed_solution",
+                                client, submission.challenge
+                            );
+                            return VerifyStatus::Invalid(submission, "malformed_solution");
                         }
                     }
-
-                    let mut raw_nonces: Vec<i32> = sol_str
-                        .split(" ")
-                        .map(|a| i32::from_str_radix(a, 16).unwrap())
-                        .collect();
-                    solution.copy_from_slice(&mut raw_nonces);
                 }
             }
 
             {
-                let mut unlocked = unsolved_requests.lock().unwrap();
+                let mut unlocked = state.unsolved.lock().unwrap();
                 unlocked.remove(&header_bytes);
+                options.metrics.pending_challenges.set(unlocked.len() as i64);
             }
 
-            if cuckoo::verify(
-                cuckoo::hash_header(&header_bytes),
-                solution,
-                easiness,
-                hash_difficulty,
-            ) {
-                println!("Verified!");
-                VerifyStatus::Valid
+            let started = Instant::now();
+            let job = p.challenge(&header_bytes).verify_job(&solution);
+            let valid = options.verifier.verify_one(job);
+            let elapsed = started.elapsed();
+            options.metrics.verify_latency.observe(elapsed);
+            let verify_us = elapsed.as_secs() * 1_000_000 + elapsed.subsec_micros() as u64;
+
+            if valid {
+                debug!(
+                    "solution verified client={} challenge={} params={} verify_us={}",
+                    client, submission.challenge, p.params, verify_us
+                );
+                VerifyStatus::Valid(submission)
             } else {
-                println!("Invalid!");
-                VerifyStatus::Invalid
+                info!(
+                    "solution rejected client={} challenge={} params={} reason=invalid_proof verify_us={}",
+                    client, submission.challenge, p.params, verify_us
+                );
+                VerifyStatus::Invalid(submission, "invalid_proof")
             }
         }
         None => VerifyStatus::Unverified,
     }
 }
 
+// Drops challenges older than the TTL. Called with the map locked.
+fn expire_challenges(unsolved: &mut RequestMap, options: &ServerOptions) {
+    let before = unsolved.len();
+    let ttl = options.challenge_ttl;
+    unsolved.retain(|_, p| p.issued.elapsed() <= ttl);
+    let expired = before - unsolved.len();
+    if expired > 0 {
+        debug!("challenges expired count={}", expired);
+        options.metrics.challenges_expired.add(expired as u64);
+    }
+}
+
+// Keeps the active connection gauge right however handle_client returns
+struct ActiveConnection<'a>(&'a Metrics);
+
+impl<'a> ActiveConnection<'a> {
+    fn new(metrics: &'a Metrics) -> ActiveConnection<'a> {
+        metrics.connections_active.inc();
+        ActiveConnection(metrics)
+    }
+}
+
+impl<'a> Drop for ActiveConnection<'a> {
+    fn drop(&mut self) {
+        self.0.connections_active.dec();
+    }
+}
+
+// Hands the request to the configured handler and writes its response
+fn dispatch(
+    h: &mut HTTPRead,
+    request: &Request,
+    options: &ServerOptions,
+    entry: &mut AccessEntry,
+    extra_headers: &[(&str, String)],
+) {
+    let handler = match options.handler {
+        Some(ref handler) => handler,
+        None => return,
+    };
+    let started = Instant::now();
+    let response = match handler.handle(request) {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("handler failed url={} error={}", request.url, e);
+            let m = Response::empty(502);
+            if h.write(m.as_bytes()).is_ok() {
+                entry.status = Some(502);
+            }
+            return;
+        }
+    };
+    options.metrics.upstream_latency.observe(started.elapsed());
+    let response = extra_headers
+        .iter()
+        .fold(response, |r, &(name, ref value)| r.with_header(name, value));
+    entry.upstream_status = response.status();
+    entry.upstream_bytes = Some(response.len());
+    if h.write(response.as_bytes()).is_ok() {
+        entry.status = entry.upstream_status;
+        entry.bytes = response.len();
+    }
+}
+
+fn request_method(msg: &[u8]) -> String {
+    let end = msg.iter().position(|c| *c == b' ' || *c == b'\t').unwrap_or(0);
+    String::from_utf8_lossy(&msg[..end]).into_owned()
+}
+
+// Last word of the request line, if it has one after the URL
+fn request_protocol(msg: &[u8]) -> String {
+    let end = msg.iter().position(|c| *c == b'\r' || *c == b'\n').unwrap_or(msg.len());
+    let line = &msg[..end];
+    match line.iter().rposition(|c| *c == b' ') {
+        Some(i) if line[..i].contains(&b' ') => String::from_utf8_lossy(&line[i + 1..]).into_owned(),
+        _ => String::new(),
+    }
+}
+
+fn verify_hook(options: &ServerOptions, client: &str, submission: &Submission, rejected: Option<&'static str>) {
+    if let Some(ref hook) = options.verify_hook {
+        hook(&VerifyEvent {
+            client,
+            challenge: &submission.challenge,
+            params: submission.problem.as_ref().map(|p| p.params),
+            rejected,
+            solve_latency: submission.solve_latency,
+        });
+    }
+}
+
+fn submission_entry(entry: &mut AccessEntry, submission: Submission) {
+    entry.challenge = Some(submission.challenge);
+    entry.params = submission.problem.map(|p| p.params);
+    entry.solve_latency = submission.solve_latency;
+}
+
+// Records a new challenge under the current policy
+fn issue_challenge(
+    state: &GatewayState,
+    options: &ServerOptions,
+    h_gen: &mut HeaderGenerator,
+    client: &str,
+) -> ([u8; HEADER_LENGTH], CuckooProblem) {
+    let new_header = h_gen.next().unwrap();
+    let problem = state.current_policy().problem();
+    debug!(
+        "challenge issued client={} challenge={} params={} easipct={} target={}",
+        client,
+        String::from_utf8_lossy(&new_header),
+        problem.params,
+        problem.easipct,
+        problem.target
+    );
+    {
+        let mut unlocked = state.unsolved.lock().unwrap();
+        expire_challenges(&mut unlocked, options);
+        unlocked.insert(new_header.to_vec(), problem.clone());
+        options.metrics.pending_challenges.set(unlocked.len() as i64);
+    }
+    options.metrics.challenges_issued.inc();
+    (new_header, problem)
+}
+
+// The interstitial page for browsers, with the placeholders filled in
+fn challenge_page(index: &[u8], header: &[u8], problem: &CuckooProblem) -> Vec<u8> {
+    let easipct_str = format!("{}", problem.easipct);
+    let target_str = format!("{}", problem.target);
+    let params_str = format!("{}", problem.params);
+
+    let header_replaced = efficient_replace(index, b"HEADER", header);
+    let easiness_replaced = efficient_replace(&header_replaced, b"EASINESS", easipct_str.as_bytes());
+    let target_replaced = efficient_replace(&easiness_replaced, b"TARGET", target_str.as_bytes());
+    let params_replaced = efficient_replace(&target_replaced, b"PARAMS", params_str.as_bytes());
+    let keying_replaced = efficient_replace(&params_replaced, b"KEYING", problem.keying.name().as_bytes());
+    format_response_binary(keying_replaced, "text/html")
+}
+
+// A 401 carrying the challenge in X-Cuckoo-* headers and a JSON body, for
+// clients that can't run the interstitial page. `error` says why the last
+// submission was turned down, if there was one. See README.md for the flow.
+fn challenge_json(
+    header: &[u8],
+    problem: &CuckooProblem,
+    options: &ServerOptions,
+    error: Option<&str>,
+) -> Vec<u8> {
+    let header = String::from_utf8_lossy(header);
+    let mut body = problem.challenge(header.as_bytes()).to_json();
+    body.pop();
+    body.push_str(&format!(",\"expires_in\":{}", options.challenge_ttl.as_secs()));
+    if let Some(error) = error {
+        body.push_str(",\"error\":");
+        json_string(&mut body, error);
+    }
+    body.push('}');
+    format!(
+        "HTTP/1.1 401 Unauthorized\r\n\
+         WWW-Authenticate: Cuckoo header=\"{}\", params=\"{}\"\r\n\
+         X-Cuckoo-Header: {}\r\n\
+         X-Cuckoo-Easiness: {}\r\n\
+         X-Cuckoo-Target: {}\r\n\
+         X-Cuckoo-Params: {}\r\n\
+         X-Cuckoo-Keying: {}\r\n\
+         Cache-Control: no-store\r\n\
+         Content-Length: {}\r\n\
+         Content-Type: application/json\r\n\
+         Connection: close\r\n\r\n{}",
+        header,
+        problem.params,
+        header,
+        problem.easipct,
+        problem.target,
+        problem.params,
+        problem.keying.name(),
+        body.len(),
+        body
+    ).into_bytes()
+}
+
+// Whether to answer with challenge_json instead of the interstitial page:
+// the path is under one of the API prefixes, or the client asked for JSON
+// and not HTML
+fn wants_json(request: &Request, options: &ServerOptions) -> bool {
+    let path = request.path();
+    if options.api_paths.iter().any(|prefix| path.starts_with(prefix.as_str())) {
+        return true;
+    }
+    match request.header("accept") {
+        Some(accept) => accept.contains("application/json") && !accept.contains("text/html"),
+        None => false,
+    }
+}
+
 fn handle_client(
     client_stream: TcpStream,
-    cached_files: HashMap<StaticResource, Vec<u8>>,
-    unsolved_requests: Arc<Mutex<RequestMap>>,
+    state: Arc<GatewayState>,
+    rng: SharedCsprng,
+    options: ServerOptions,
 ) {
+    let _active = ActiveConnection::new(&options.metrics);
+
     client_stream
         .set_read_timeout(Some(Duration::new(20, 0)))
         .unwrap();
@@ -492,15 +1140,22 @@ fn handle_client(
         .set_write_timeout(Some(Duration::new(5, 0)))
         .unwrap();
 
-    let mut rng = thread_rng();
-    let u8_gen = rng.gen_ascii_chars();
-    let mut h_gen = HeaderGenerator {
-        u8_gen: u8_gen,
-        tmp: Vec::new(),
+    let peer = client_stream.peer_addr().ok();
+    let (client, client_ip) = match peer {
+        Some(addr) => (addr.to_string(), addr.ip().to_string()),
+        None => (String::from("-"), String::from("-")),
     };
 
+    let mut h_gen = HeaderGenerator::new(rng);
+
     let mut h = HTTPRead::new(client_stream, BUF_SIZE);
 
+    let log = |entry: &AccessEntry| {
+        if let Some(ref access_log) = options.access_log {
+            access_log.record(entry);
+        }
+    };
+
     loop {
         h_gen.regenerate();
         let msg_raw = h.next();
@@ -510,126 +1165,654 @@ fn handle_client(
         let (msg, url) = msg_raw.unwrap();
 
         //println!("{:?}", msg);
-        //println!("URL: {:?}", url);
-
-        if url == b"/web_miner.wasm" {
-            // TODO: Take this conversion out of HTTP request handling...
-            let m = cached_files.get(&StaticResource::WebMinerWasm).unwrap();
+        let method = request_method(&msg);
+        let url_str = String::from_utf8_lossy(&url).into_owned();
+        debug!("request client={} method={} url={}", client, method, url_str);
+        let protocol = request_protocol(&msg);
+        let new_entry = |outcome| {
+            let mut entry = AccessEntry::new(&client_ip, &method, &url_str, outcome);
+            entry.protocol = protocol.clone();
+            entry
+        };
 
-            if h.write(m).is_err() {
-                h.close();
-            } else {
-                h.close();
+        if peer.is_some_and(|addr| state.is_denied(&addr.ip())) {
+            debug!("request denied client={}", client);
+            let mut entry = new_entry(Outcome::Denied);
+            let m = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+            if h.write(m).is_ok() {
+                entry.status = Some(403);
             }
+            h.close();
+            log(&entry);
 
             return;
-        } else if url == b"/web_miner.js" {
-            let m = cached_files.get(&StaticResource::WebMinerJS).unwrap();
+        }
 
-            if h.write(m).is_err() {
-                h.close();
+        let assets = state.assets();
+
+        if url == b"/web_miner.wasm" || url == b"/web_miner.js" {
+            // TODO: Take this conversion out of HTTP request handling...
+            let resource = if url == b"/web_miner.wasm" {
+                StaticResource::WebMinerWasm
             } else {
-                h.close();
+                StaticResource::WebMinerJS
+            };
+
+            let mut entry = new_entry(Outcome::Static);
+            match assets.get(&resource) {
+                Some(m) => {
+                    if h.write(m).is_ok() {
+                        entry.status = Some(200);
+                        entry.bytes = m.len();
+                        options.metrics.bytes_served.add(resource.name(), m.len() as u64);
+                    }
+                }
+                None => warn!("asset missing name={}", resource.name()),
             }
+            h.close();
+            log(&entry);
 
             return;
         }
 
-        match verified(unsolved_requests.clone(), &msg) {
-            VerifyStatus::Unverified => {
-                if requires_cuckoo(&url) {
-                    // Reply with request details
-                    let index = cached_files.get(&StaticResource::WebMinerHtml).unwrap();
-                    let new_header = h_gen.next().unwrap();
+        let request = Request {
+            client: client_ip.clone(),
+            method: method.clone(),
+            url: url_str.clone(),
+            raw: msg,
+        };
 
-                    let problem = CuckooProblem {
-                        easipct: EASIPCT,
-                        difficulty: DIFFICULTY,
-                    };
+        if peer.is_some_and(|addr| state.is_allowed(&addr.ip())) {
+            // Forward it to the server
+            debug!("request allowed client={}", client);
+            let mut entry = new_entry(Outcome::Allowed);
+            dispatch(&mut h, &request, &options, &mut entry, &[]);
+            h.close();
+            log(&entry);
+            continue;
+        }
 
-                    let easipct_str = format!("{}", EASIPCT);
-                    let difficulty_str = format!("{}", DIFFICULTY);
+        if options.clearance_ttl.is_some() {
+            let token = efficient_parse_header(&request.raw, b"X-Cuckoo-Clearance: ");
+            if token.is_some_and(|token| state.is_cleared(&token)) {
+                debug!("request cleared client={}", client);
+                let mut entry = new_entry(Outcome::Cleared);
+                dispatch(&mut h, &request, &options, &mut entry, &[]);
+                h.close();
+                log(&entry);
+                continue;
+            }
+        }
 
-                    let header_replaced = efficient_replace(index, b"HEADER", &new_header);
-                    let easiness_replaced =
-                        efficient_replace(&header_replaced, b"EASINESS", easipct_str.as_bytes());
-                    let difficulty_replaced = efficient_replace(
-                        &easiness_replaced,
-                        b"DIFFICULTY",
-                        difficulty_str.as_bytes(),
-                    );
-                    let m = format_response_binary(difficulty_replaced, "text/html");
+        match verified(&state, &request.raw, &client, &options) {
+            VerifyStatus::Unverified => {
+                if requires_cuckoo(&url) {
+                    let json = wants_json(&request, &options);
+                    let index = match assets.get(&StaticResource::WebMinerHtml) {
+                        Some(index) => index,
+                        None if json => &[][..],
+                        None => {
+                            warn!("asset missing name={}", StaticResource::WebMinerHtml.name());
+                            h.close();
+                            return;
+                        }
+                    };
+                    let (new_header, problem) = issue_challenge(&state, &options, &mut h_gen, &client);
 
-                    {
-                        unsolved_requests
-                            .lock()
-                            .unwrap()
-                            .insert(new_header.to_vec(), problem);
-                    }
+                    let mut entry = new_entry(Outcome::Challenged);
+                    entry.challenge = Some(String::from_utf8_lossy(&new_header).into_owned());
+                    entry.params = Some(problem.params);
 
-                    if h.write(&m).is_err() {
-                        h.close();
+                    let (m, status, resource) = if json {
+                        (challenge_json(&new_header, &problem, &options, None), 401, "challenge.json")
                     } else {
-                        // Then drop the connection
-                        h.close();
+                        (challenge_page(index, &new_header, &problem), 200, StaticResource::WebMinerHtml.name())
+                    };
+                    if h.write(&m).is_ok() {
+                        entry.status = Some(status);
+                        entry.bytes = m.len();
+                        options.metrics.bytes_served.add(resource, m.len() as u64);
                     }
+                    // Then drop the connection
+                    h.close();
+                    log(&entry);
 
                     return;
                 } else {
                     // Forward it to the server
+                    let mut entry = new_entry(Outcome::Exempt);
+                    dispatch(&mut h, &request, &options, &mut entry, &[]);
+                    h.close();
+                    log(&entry);
                 }
             }
-            VerifyStatus::Invalid => {
+            VerifyStatus::Invalid(submission, reason) => {
+                let mut entry = new_entry(Outcome::Rejected(reason));
+                options.metrics.challenges_failed.inc(reason);
+                verify_hook(&options, &client_ip, &submission, Some(reason));
+                submission_entry(&mut entry, submission);
+                if wants_json(&request, &options) {
+                    // API clients get a fresh challenge to retry with
+                    let (new_header, problem) = issue_challenge(&state, &options, &mut h_gen, &client);
+                    let m = challenge_json(&new_header, &problem, &options, Some(reason));
+                    if h.write(&m).is_ok() {
+                        entry.status = Some(401);
+                        entry.bytes = m.len();
+                        options.metrics.bytes_served.add("challenge.json", m.len() as u64);
+                    }
+                }
                 h.close();
+                log(&entry);
             }
-            VerifyStatus::Valid => {
+            VerifyStatus::Valid(submission) => {
                 // Forward sub-message to the server
+                let mut entry = new_entry(Outcome::Verified);
+                options.metrics.challenges_solved.inc();
+                verify_hook(&options, &client_ip, &submission, None);
+                submission_entry(&mut entry, submission);
+                let mut extra_headers = Vec::new();
+                if let Some(ttl) = options.clearance_ttl {
+                    let token = h_gen.next().unwrap();
+                    state.grant_clearance(&token, ttl);
+                    extra_headers.push((
+                        "X-Cuckoo-Clearance",
+                        String::from_utf8_lossy(&token).into_owned(),
+                    ));
+                    extra_headers.push(("X-Cuckoo-Clearance-Max-Age", ttl.as_secs().to_string()));
+                }
+                dispatch(&mut h, &request, &options, &mut entry, &extra_headers);
                 h.close();
+                log(&entry);
             }
         }
     }
 }
 
+// Handed to ServerOptions::verify_hook for every submitted solution
+#[derive(Debug)]
+pub struct VerifyEvent<'a> {
+    pub client: &'a str,
+    pub challenge: &'a str,
+    // None if the challenge was unknown
+    pub params: Option<cuckoo::Params>,
+    // None if the proof verified, otherwise why it didn't
+    pub rejected: Option<&'static str>,
+    pub solve_latency: Option<Duration>,
+}
+
+pub type VerifyHook = Arc<dyn Fn(&VerifyEvent) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct ServerOptions {
+    // Where to write one line per request, if anywhere
+    pub access_log: Option<Arc<AccessLog>>,
+    pub metrics: Arc<Metrics>,
+    // Serves the admin API and GET /metrics when set. Keep it off the
+    // public interface.
+    pub admin_addr: Option<String>,
+    // Bearer token for the admin API. One is generated and printed to
+    // stderr if unset.
+    pub admin_token: Option<String>,
+    // Unsolved challenges older than this are dropped
+    pub challenge_ttl: Duration,
+    // How long shutdown waits for in-flight connections
+    pub drain_timeout: Duration,
+    // Outstanding challenges are saved here on shutdown and loaded on
+    // startup, so a restart doesn't invalidate them
+    pub state_path: Option<String>,
+    // Serves verified, allowed and exempt requests. Without one they get
+    // no response.
+    pub handler: Option<Arc<dyn Handler>>,
+    // Unverified requests under these path prefixes get the JSON challenge
+    // whatever they accept
+    pub api_paths: Vec<String>,
+    // If set, a verified response carries an X-Cuckoo-Clearance token that
+    // gets the client through without solving again for this long
+    pub clearance_ttl: Option<Duration>,
+    pub verify_hook: Option<VerifyHook>,
+    // Checks submitted proofs. Its workers bound how much CPU a flood of
+    // submissions can take, however many connections carry them.
+    pub verifier: Arc<BatchVerifier>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> ServerOptions {
+        ServerOptions {
+            access_log: None,
+            metrics: Metrics::new(),
+            admin_addr: None,
+            admin_token: None,
+            challenge_ttl: Duration::new(CHALLENGE_TTL_SECS, 0),
+            drain_timeout: Duration::new(DRAIN_TIMEOUT_SECS, 0),
+            state_path: None,
+            handler: None,
+            api_paths: Vec::new(),
+            clearance_ttl: None,
+            verify_hook: None,
+            verifier: Arc::new(BatchVerifier::new()),
+        }
+    }
+}
+
 pub fn server_start(local_ip: String) {
-    let listener = TcpListener::bind(local_ip.clone()).unwrap();
-    let unsolved_requests = Arc::new(Mutex::new(HashMap::new()));
-    for stream in listener.incoming() {
-        if stream.is_err() {
-            continue;
+    server_start_with(local_ip, ServerOptions::default())
+}
+
+// Serves until the listener fails. Binaries that want SIGTERM and SIGINT
+// to drain and persist should use ServerHandle::shutdown_on_signal.
+pub fn server_start_with(local_ip: String, options: ServerOptions) {
+    let handle = spawn_server(bind(&local_ip).unwrap(), options).unwrap();
+    handle.wait();
+}
+
+// Takes over a listening socket passed down by a supervisor (systemd socket
+// activation, or a previous instance handing off for a restart) as fd 3.
+#[cfg(unix)]
+pub fn inherited_listener() -> Option<TcpListener> {
+    use std::env;
+    use std::os::unix::io::FromRawFd;
+    use std::process;
+
+    let fds = env::var("LISTEN_FDS").ok()?.parse::<u32>().ok()?;
+    if fds < 1 {
+        return None;
+    }
+    // The variables are inherited by every descendant, so only the process
+    // they were meant for may act on them. ServerHandle::hand_off can't
+    // know its successor's pid before it runs and leaves LISTEN_PID unset.
+    if let Ok(pid) = env::var("LISTEN_PID") {
+        if pid.parse::<u32>().ok()? != process::id() {
+            return None;
         }
+    }
+    // Don't let our own children think the socket is theirs
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_PID");
+    if !is_listening_tcp(LISTEN_FDS_START) {
+        warn!("inherited fd is not a listening tcp socket fd={}", LISTEN_FDS_START);
+        return None;
+    }
+    Some(unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) })
+}
 
-        let mut st = HashMap::new();
-        st.insert(
-            StaticResource::WebMinerHtml,
-            fs::read("static/index.html").unwrap(),
-        );
-        st.insert(
-            StaticResource::WebMinerJS,
-            format_response_text(
-                &mut fs::read_to_string("target/wasm32-unknown-unknown/release/web_miner.js")
-                    .unwrap(),
-                "application/javascript",
-            ).as_bytes()
-                .to_vec(),
-        );
-        st.insert(
-            StaticResource::WebMinerWasm,
-            format_response_binary(
-                fs::read("target/wasm32-unknown-unknown/release/web_miner.wasm").unwrap(),
-                "application/wasm",
-            ),
+// Whether `fd` is a socket that has had listen() called on it, with an
+// IPv4 or IPv6 address
+#[cfg(unix)]
+fn is_listening_tcp(fd: i32) -> bool {
+    use std::mem;
+
+    let mut accepting: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let listening = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut accepting as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    } == 0 && accepting != 0;
+
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let inet = unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } == 0
+        && (addr.ss_family as libc::c_int == libc::AF_INET || addr.ss_family as libc::c_int == libc::AF_INET6);
+
+    let mut kind: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let stream = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    } == 0 && kind == libc::SOCK_STREAM;
+
+    listening && inet && stream
+}
+
+#[cfg(not(unix))]
+pub fn inherited_listener() -> Option<TcpListener> {
+    None
+}
+
+// The inherited listener if there is one, otherwise a fresh bind
+pub fn bind(local_ip: &str) -> io::Result<TcpListener> {
+    match inherited_listener() {
+        Some(listener) => {
+            info!("listener inherited addr={}", listener.local_addr()?);
+            Ok(listener)
+        }
+        None => TcpListener::bind(local_ip),
+    }
+}
+
+// Sockets of connections being handled, so shutdown can wait on them and
+// cut off whatever outlives the drain
+struct Connections {
+    open: Mutex<(u64, HashMap<u64, TcpStream>)>,
+    closed: Condvar,
+}
+
+impl Connections {
+    fn new() -> Connections {
+        Connections {
+            open: Mutex::new((0, HashMap::new())),
+            closed: Condvar::new(),
+        }
+    }
+
+    fn register(&self, stream: &TcpStream) -> u64 {
+        let mut open = self.open.lock().unwrap();
+        open.0 += 1;
+        let id = open.0;
+        if let Ok(s) = stream.try_clone() {
+            open.1.insert(id, s);
+        }
+        id
+    }
+
+    fn finish(&self, id: u64) {
+        self.open.lock().unwrap().1.remove(&id);
+        self.closed.notify_all();
+    }
+
+    // Waits for every connection to finish. Returns how many were still
+    // open at the deadline and had to be shut down.
+    fn drain(&self, deadline: Instant) -> usize {
+        let mut open = self.open.lock().unwrap();
+        while !open.1.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            open = self.closed.wait_timeout(open, deadline - now).unwrap().0;
+        }
+        for s in open.1.values() {
+            s.shutdown(Shutdown::Both).ok();
+        }
+        open.1.len()
+    }
+}
+
+struct Finish<'a>(&'a Connections, u64);
+
+impl<'a> Drop for Finish<'a> {
+    fn drop(&mut self) {
+        self.0.finish(self.1);
+    }
+}
+
+// A listener's accept loop, running on its own thread until stopped
+struct Acceptor {
+    addr: SocketAddr,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Acceptor {
+    // accept() has no timeout, so poke it with a connection of our own
+    fn stop(self) {
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            let loopback: IpAddr = match addr {
+                SocketAddr::V4(_) => Ipv4Addr::new(127, 0, 0, 1).into(),
+                SocketAddr::V6(_) => Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1).into(),
+            };
+            addr.set_ip(loopback);
+        }
+        TcpStream::connect(addr).ok();
+        self.thread.join().ok();
+    }
+}
+
+pub struct ServerHandle {
+    stopping: Arc<AtomicBool>,
+    state: Arc<GatewayState>,
+    options: ServerOptions,
+    connections: Arc<Connections>,
+    gateways: Vec<Acceptor>,
+    admin: Option<Acceptor>,
+    // Copies of the gateway listeners, which outlive the acceptors for
+    // hand_off
+    listeners: Vec<TcpListener>,
+}
+
+// Starts serving on `listener` in the background. Loads any challenges
+// persisted by a previous shutdown from options.state_path.
+pub fn spawn_server(listener: TcpListener, options: ServerOptions) -> io::Result<ServerHandle> {
+    spawn_gateway(vec![listener], GatewayState::new(), options)
+}
+
+// Like spawn_server, for any number of listeners sharing one state
+pub fn spawn_gateway(
+    listeners: Vec<TcpListener>,
+    state: GatewayState,
+    options: ServerOptions,
+) -> io::Result<ServerHandle> {
+    let state = Arc::new(state);
+    if let Err(e) = state.reload_assets() {
+        warn!("assets not loaded error={}", e);
+    }
+    if let Some(ref path) = options.state_path {
+        match state.load_challenges(path, options.challenge_ttl) {
+            Ok(n) => info!("challenges restored count={} path={}", n, path),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("challenges not restored path={} error={}", path, e),
+        }
+        options
+            .metrics
+            .pending_challenges
+            .set(state.pending_challenges() as i64);
+    }
+    let rng = Csprng::new()?.shared();
+    let stopping = Arc::new(AtomicBool::new(false));
+
+    let admin = match options.admin_addr {
+        Some(ref admin_addr) => {
+            let token = match options.admin_token {
+                Some(ref token) => token.clone(),
+                None => {
+                    let token = rng.lock().unwrap().gen_token(ADMIN_TOKEN_BYTES);
+                    // Straight to the operator, never through the log
+                    // facade where it could end up in shipped logs
+                    eprintln!("cuckoo_http admin token: {}", token);
+                    warn!("admin token generated and printed to stderr");
+                    token
+                }
+            };
+            let admin_listener = TcpListener::bind(admin_addr.as_str())?;
+            let addr = admin_listener.local_addr()?;
+            info!("admin listening addr={}", addr);
+            let (state, options, stopping) = (state.clone(), options.clone(), stopping.clone());
+            Some(Acceptor {
+                addr,
+                thread: thread::spawn(move || admin::serve(admin_listener, state, options, token, stopping)),
+            })
+        }
+        None => None,
+    };
+
+    let connections = Arc::new(Connections::new());
+    let mut gateways = Vec::new();
+    let mut copies = Vec::new();
+    for listener in listeners {
+        let local_addr = listener.local_addr()?;
+        copies.push(listener.try_clone()?);
+        info!("listening addr={}", local_addr);
+        let (state, rng, options, stopping, connections) = (
+            state.clone(),
+            rng.clone(),
+            options.clone(),
+            stopping.clone(),
+            connections.clone(),
         );
+        gateways.push(Acceptor {
+            addr: local_addr,
+            thread: thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopping.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("accept failed error={}", e);
+                            continue;
+                        }
+                    };
+                    options.metrics.connections_accepted.inc();
+
+                    let id = connections.register(&stream);
+                    let state_copy = state.clone();
+                    let rng_copy = rng.clone();
+                    let options_copy = options.clone();
+                    let connections_copy = connections.clone();
+                    thread::spawn(move || {
+                        let _finish = Finish(&connections_copy, id);
+                        handle_client(stream, state_copy, rng_copy, options_copy)
+                    });
+                }
+            }),
+        });
+    }
+
+    Ok(ServerHandle {
+        stopping,
+        state,
+        options,
+        connections,
+        gateways,
+        admin,
+        listeners: copies,
+    })
+}
+
+impl ServerHandle {
+    // Address of the first gateway listener
+    pub fn local_addr(&self) -> SocketAddr {
+        self.gateways[0].addr
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.gateways.iter().map(|g| g.addr).collect()
+    }
+
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin.as_ref().map(|a| a.addr)
+    }
+
+    pub fn state(&self) -> Arc<GatewayState> {
+        self.state.clone()
+    }
+
+    // Stops accepting, gives in-flight connections until options.drain_timeout
+    // to finish, then saves outstanding challenges to options.state_path
+    pub fn shutdown(self) -> io::Result<()> {
+        info!("shutting down");
+        self.stopping.store(true, Ordering::SeqCst);
+        for gateway in self.gateways {
+            gateway.stop();
+        }
+        if let Some(admin) = self.admin {
+            admin.stop();
+        }
+
+        let cut = self
+            .connections
+            .drain(Instant::now() + self.options.drain_timeout);
+        if cut > 0 {
+            warn!("connections cut at drain deadline count={}", cut);
+        }
 
-        let unsolved_requests_copy = unsolved_requests.clone();
-        thread::spawn(move || handle_client(stream.unwrap(), st, unsolved_requests_copy));
+        if let Some(ref path) = self.options.state_path {
+            let n = self.state.save_challenges(path)?;
+            info!("challenges saved count={} path={}", n, path);
+        }
+        info!("shutdown complete");
+        Ok(())
+    }
+
+    // Shuts down as above, then starts `successor` with the first gateway
+    // listener as fd 3 and LISTEN_FDS=1, for it to take over with
+    // inherited_listener. The socket is never closed, so connections that
+    // arrive in between wait in its backlog rather than being refused.
+    // Challenges are saved before the successor starts, for it to load
+    // from the same state_path.
+    #[cfg(unix)]
+    pub fn hand_off(mut self, mut successor: Command) -> io::Result<Child> {
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::process::CommandExt;
+
+        let listener = self.listeners.remove(0);
+        self.shutdown()?;
+
+        let fd = listener.as_raw_fd();
+        successor.env("LISTEN_FDS", "1").env_remove("LISTEN_PID");
+        // Only async-signal-safe calls between fork and exec. dup2 clears
+        // close-on-exec on the copy, but does nothing if it's already fd 3.
+        unsafe {
+            successor.pre_exec(move || {
+                let moved = if fd == LISTEN_FDS_START {
+                    libc::fcntl(fd, libc::F_SETFD, 0)
+                } else {
+                    libc::dup2(fd, LISTEN_FDS_START)
+                };
+                if moved < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = successor.spawn()?;
+        info!("listener handed off pid={}", child.id());
+        Ok(child)
+    }
+
+    // Blocks until SIGTERM or SIGINT arrives, then shuts down
+    #[cfg(unix)]
+    pub fn shutdown_on_signal(self) -> io::Result<()> {
+        let signalled = Arc::new(AtomicBool::new(false));
+        for sig in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT].iter() {
+            signal_hook::flag::register(*sig, signalled.clone())?;
+        }
+        while !signalled.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(SIGNAL_POLL_MS));
+        }
+        self.shutdown()
+    }
+
+    #[cfg(not(unix))]
+    pub fn shutdown_on_signal(self) -> io::Result<()> {
+        self.wait();
+        Ok(())
+    }
+
+    // Blocks for as long as the listeners keep accepting
+    pub fn wait(self) {
+        for gateway in self.gateways {
+            gateway.thread.join().ok();
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use http_server::{efficient_parse_header, efficient_replace, server_start};
+    use csprng::Csprng;
+    use cuckoo::KeyingScheme;
+    use http_server::{
+        efficient_parse_header, efficient_replace, log_safe, request_protocol, server_start,
+        spawn_server, GatewayState, HeaderGenerator, PuzzlePolicy, ServerOptions,
+    };
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::net::TcpListener;
     use std::io::Write;
     use std::net::TcpStream;
+    #[cfg(unix)]
+    use std::process::Command;
     use std::sync::mpsc;
     use std::thread;
     use std::time::Duration;
@@ -678,6 +1861,33 @@ mod tests {
         assert_eq!(&efficient_parse_header(c, d).unwrap(), b"abcde f1234");
     }
 
+    #[test]
+    fn request_protocol_works() {
+        assert_eq!(request_protocol(b"GET /a HTTP/1.1\r\nHost: x y\r\n\r\n"), "HTTP/1.1");
+        assert_eq!(request_protocol(b"GET /a\r\nHost: x y\r\n\r\n"), "");
+    }
+
+    #[test]
+    fn log_safe_neutralizes_headers() {
+        assert_eq!(log_safe(b"Zx9abc"), "Zx9abc");
+        assert_eq!(log_safe(b"a b\nc=d\x1b"), "a?b?c=d?");
+        let long = [b'x'; 40];
+        assert_eq!(log_safe(&long), format!("{}...", "x".repeat(32)));
+    }
+
+    #[test]
+    fn header_generator_is_injectable() {
+        let mut a = HeaderGenerator::new(Csprng::from_seed(&[42]).shared());
+        let mut b = HeaderGenerator::new(Csprng::from_seed(&[42]).shared());
+
+        let headers: Vec<_> = a.by_ref().take(20).collect();
+        assert_eq!(headers, b.by_ref().take(20).collect::<Vec<_>>());
+        for h in headers.iter() {
+            assert!(h.iter().all(|c| (*c as char).is_ascii_alphanumeric()));
+        }
+        assert!(headers[0] != headers[1]);
+    }
+
     #[test]
     fn get_works() {
         let tx = set_up_connection();
@@ -695,4 +1905,82 @@ mod tests {
 
         thread::sleep(Duration::new(3, 0));
     }
+
+    #[test]
+    fn challenges_survive_restart() {
+        let path = env::temp_dir().join(format!("cuckoo_http_{}.state", ::std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        let state = GatewayState::new();
+        let policy = PuzzlePolicy {
+            keying: KeyingScheme::Blake2b256,
+            ..PuzzlePolicy::default()
+        };
+        {
+            let mut unsolved = state.unsolved.lock().unwrap();
+            unsolved.insert(b"fresh".to_vec(), policy.problem());
+            let mut stale = policy.problem();
+            stale.issued -= Duration::new(120, 0);
+            unsolved.insert(b"stale".to_vec(), stale);
+        }
+        assert_eq!(state.save_challenges(&path).unwrap(), 2);
+        assert!(fs::read_to_string(&path).unwrap().contains(" blake2b256 "));
+
+        let restored = GatewayState::new();
+        assert_eq!(restored.load_challenges(&path, Duration::new(60, 0)).unwrap(), 1);
+        let challenges = restored.challenges();
+        assert_eq!(challenges[0].id, "fresh");
+        assert_eq!(challenges[0].params, policy.params);
+        assert_eq!(restored.unsolved.lock().unwrap()[&b"fresh".to_vec()].keying, KeyingScheme::Blake2b256);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn shutdown_drains_and_persists() {
+        let path = env::temp_dir().join(format!("cuckoo_http_{}.drain", ::std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let options = ServerOptions {
+            drain_timeout: Duration::from_millis(200),
+            state_path: Some(path.clone()),
+            ..ServerOptions::default()
+        };
+        let handle = spawn_server(TcpListener::bind("127.0.0.1:0").unwrap(), options).unwrap();
+        let addr = handle.local_addr();
+        handle
+            .state()
+            .unsolved
+            .lock()
+            .unwrap()
+            .insert(b"pending".to_vec(), PuzzlePolicy::default().problem());
+
+        // Never sends a request, so it is only let go at the drain deadline
+        let mut idle = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        handle.shutdown().unwrap();
+
+        let mut buf = Vec::new();
+        assert_eq!(idle.read_to_end(&mut buf).unwrap_or(0), 0);
+        assert!(TcpStream::connect(addr).is_err());
+        assert!(fs::read_to_string(&path).unwrap().starts_with("pending cuckoo22 "));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hand_off_passes_the_listener() {
+        let handle = spawn_server(TcpListener::bind("127.0.0.1:0").unwrap(), ServerOptions::default()).unwrap();
+        let addr = handle.local_addr();
+
+        // Stands in for the successor: holds fd 3 open for a while if it
+        // got a socket there under LISTEN_FDS
+        let mut successor = Command::new("sh");
+        successor
+            .arg("-c")
+            .arg("test \"$LISTEN_FDS\" = 1 && test -z \"$LISTEN_PID\" && test -S /proc/self/fd/3 && sleep 1");
+        let mut child = handle.hand_off(successor).unwrap();
+
+        // Nobody accepts it, but the socket is still open in the successor
+        assert!(TcpStream::connect(addr).is_ok());
+        assert!(child.wait().unwrap().success());
+    }
 }
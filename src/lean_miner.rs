@@ -0,0 +1,378 @@
+const MAXPATHLEN: usize = 4096;
+pub const NTRIMS: usize = 32;
+
+use std::cmp::min as _min;
+use std::collections::HashMap;
+
+use cuckoo::Edge;
+use cuckoo::EdgeGen;
+use cuckoo::PROOFSIZE;
+use cuckoo::Params;
+use cuckoo::Proof;
+use simple_miner::CuckooSolve;
+use solver::{SolveControl, SolveStatus, CHECK_INTERVAL};
+
+struct Bitmap {
+    bits: Vec<u64>,
+}
+
+impl Bitmap {
+    fn new(len: usize) -> Bitmap {
+        Bitmap {
+            bits: vec![0; len.div_ceil(64)],
+        }
+    }
+
+    fn full(len: usize) -> Bitmap {
+        let mut b = Bitmap {
+            bits: vec![!0; len.div_ceil(64)],
+        };
+        if !len.is_multiple_of(64) {
+            let last = b.bits.len() - 1;
+            b.bits[last] = (1u64 << (len % 64)) - 1;
+        }
+        b
+    }
+
+    #[inline]
+    fn get(&self, i: usize) -> bool {
+        (self.bits[i >> 6] >> (i & 63)) & 1 != 0
+    }
+
+    #[inline]
+    fn set(&mut self, i: usize) {
+        self.bits[i >> 6] |= 1 << (i & 63);
+    }
+
+    #[inline]
+    fn reset(&mut self, i: usize) {
+        self.bits[i >> 6] &= !(1 << (i & 63));
+    }
+
+    fn clear(&mut self) {
+        for w in self.bits.iter_mut() {
+            *w = 0;
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.bits.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    fn ones<'a>(&'a self) -> impl Iterator<Item = usize> + 'a {
+        self.bits.iter().enumerate().flat_map(|(i, w)| {
+            let w = *w;
+            (0..64).filter(move |b| (w >> b) & 1 != 0).map(move |b| i * 64 + b)
+        })
+    }
+}
+
+// Two bits of degree per node: seen once, seen at least twice
+struct DegreeCounter {
+    once: Bitmap,
+    twice: Bitmap,
+}
+
+impl DegreeCounter {
+    fn new(nodes: usize) -> DegreeCounter {
+        DegreeCounter {
+            once: Bitmap::new(nodes),
+            twice: Bitmap::new(nodes),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.once.clear();
+        self.twice.clear();
+    }
+
+    #[inline]
+    fn add(&mut self, node: usize) {
+        if self.once.get(node) {
+            self.twice.set(node);
+        } else {
+            self.once.set(node);
+        }
+    }
+
+    #[inline]
+    fn is_leaf(&self, node: usize) -> bool {
+        !self.twice.get(node)
+    }
+}
+
+// Follows cuckoo paths over a sparse set of edges, remembering which nonce
+// produced each stored link so cycles can be reported without a rescan
+pub struct CycleFinder {
+    nedges: i32,
+    cuckoo: HashMap<i32, (i32, i32)>,
+    us: Vec<i32>,
+    vs: Vec<i32>,
+}
+
+impl CycleFinder {
+    pub fn new(params: Params) -> CycleFinder {
+        CycleFinder {
+            nedges: params.nedges(),
+            cuckoo: HashMap::new(),
+            us: vec![0; MAXPATHLEN],
+            vs: vec![0; MAXPATHLEN],
+        }
+    }
+
+    fn path(&self, mut u: i32, us: &mut [i32]) -> Option<usize> {
+        let mut nu: usize = 0;
+        loop {
+            match self.cuckoo.get(&u) {
+                None => return Some(nu),
+                Some(&(next, _)) => {
+                    nu += 1;
+                    if nu >= MAXPATHLEN {
+                        return None;
+                    }
+                    us[nu] = next;
+                    u = next;
+                }
+            }
+        }
+    }
+
+    fn nonce(&self, node: i32) -> i32 {
+        self.cuckoo[&node].1
+    }
+
+    // Adds an edge to the forest. If it closes a cycle instead, the edge is
+    // left out and the sorted nonces of the cycle are returned.
+    pub fn add_edge(&mut self, nonce: i32, e: Edge) -> Option<Vec<i32>> {
+        let mut us = ::std::mem::take(&mut self.us);
+        let mut vs = ::std::mem::take(&mut self.vs);
+        let cycle = self.add_edge_with(nonce, e, &mut us, &mut vs);
+        self.us = us;
+        self.vs = vs;
+        cycle
+    }
+
+    fn add_edge_with(
+        &mut self,
+        nonce: i32,
+        e: Edge,
+        us: &mut [i32],
+        vs: &mut [i32],
+    ) -> Option<Vec<i32>> {
+        us[0] = e.u;
+        vs[0] = self.nedges + e.v;
+
+        let nu_raw = self.path(us[0], us);
+        let nv_raw = self.path(vs[0], vs);
+        if nu_raw.is_none() || nv_raw.is_none() {
+            return None;
+        }
+        let mut nu = nu_raw.unwrap();
+        let mut nv = nv_raw.unwrap();
+
+        if us[nu] == vs[nv] {
+            let min = _min(nu, nv);
+            nu -= min;
+            nv -= min;
+            while us[nu] != vs[nv] {
+                nu += 1;
+                nv += 1;
+            }
+
+            let mut cycle = Vec::with_capacity(nu + nv + 1);
+            cycle.push(nonce);
+            cycle.extend(us[..nu].iter().map(|&u| self.nonce(u)));
+            cycle.extend(vs[..nv].iter().map(|&v| self.nonce(v)));
+            cycle.sort();
+            return Some(cycle);
+        }
+
+        // Reverse the shorter path, carrying each link's nonce along
+        if nu < nv {
+            while nu != 0 {
+                nu -= 1;
+                let link = self.nonce(us[nu]);
+                self.cuckoo.insert(us[nu + 1], (us[nu], link));
+            }
+            self.cuckoo.insert(us[0], (vs[0], nonce));
+        } else {
+            while nv != 0 {
+                nv -= 1;
+                let link = self.nonce(vs[nv]);
+                self.cuckoo.insert(vs[nv + 1], (vs[nv], link));
+            }
+            self.cuckoo.insert(vs[0], (us[0], nonce));
+        }
+        None
+    }
+}
+
+// Runs `ntrims` rounds of leaf removal, alternating between the u and v
+// sides, and returns the nonces that survive
+pub fn trim(cs: &CuckooSolve, ntrims: usize) -> Vec<i32> {
+    trim_until(cs, ntrims, |_| false)
+}
+
+// Like trim, but also stops once both sides have been trimmed and `done`
+// accepts the number of surviving edges
+pub fn trim_until<F: Fn(usize) -> bool>(cs: &CuckooSolve, ntrims: usize, done: F) -> Vec<i32> {
+    trim_with(cs, ntrims, done, &mut SolveControl::new()).unwrap_or_default()
+}
+
+// Stops early with the status the control reports. It is boxed because a
+// SolveStatus has room for a whole proof.
+pub fn trim_with<F: Fn(usize) -> bool>(
+    cs: &CuckooSolve,
+    ntrims: usize,
+    done: F,
+    control: &mut SolveControl,
+) -> Result<Vec<i32>, Box<SolveStatus>> {
+    let limit = cs.params.nonce_limit(cs.easiness) as usize;
+    let mut alive = Bitmap::full(limit);
+    let mut degrees = DegreeCounter::new(cs.params.nedges() as usize);
+    let mut edges = EdgeGen::new(cs.params, cs.graph_v);
+
+    let mut remaining = limit;
+    let mut idle_rounds = 0;
+    for round in 0..ntrims {
+        if let Some(status) = control.checkpoint(round as f64 / (ntrims + 1) as f64) {
+            return Err(Box::new(status));
+        }
+        let side = |e: Edge| {
+            if round % 2 == 0 {
+                e.u as usize
+            } else {
+                e.v as usize
+            }
+        };
+
+        degrees.clear();
+        for (i, nonce) in alive.ones().enumerate() {
+            if i % CHECK_INTERVAL == 0 {
+                if let Some(status) = control.should_stop() {
+                    return Err(Box::new(status));
+                }
+            }
+            degrees.add(side(edges.edge(nonce as i32)));
+        }
+
+        let mut dead = Vec::new();
+        for nonce in alive.ones() {
+            if degrees.is_leaf(side(edges.edge(nonce as i32))) {
+                dead.push(nonce);
+            }
+        }
+        for nonce in dead.iter() {
+            alive.reset(*nonce);
+        }
+
+        // Nothing left to trim once both sides stop shrinking
+        let left = alive.count();
+        idle_rounds = if left == remaining { idle_rounds + 1 } else { 0 };
+        if idle_rounds == 2 || (round % 2 == 1 && done(left)) {
+            break;
+        }
+        remaining = left;
+    }
+
+    Ok(alive.ones().map(|n| n as i32).collect())
+}
+
+// Feeds edges to a CycleFinder until one closes a proof that meets the
+// difficulty. Shared by the trimming miners.
+pub fn search<I: Iterator<Item = (i32, Edge)>>(
+    cs: &CuckooSolve,
+    edges: I,
+    control: &mut SolveControl,
+) -> SolveStatus {
+    let mut finder = CycleFinder::new(cs.params);
+    for (i, (nonce, e)) in edges.enumerate() {
+        if i % CHECK_INTERVAL == 0 {
+            if let Some(status) = control.should_stop() {
+                return status;
+            }
+        }
+        let cycle = finder.add_edge(nonce, e);
+        if cycle.is_some() {
+            control.cycle_found();
+        }
+        match cycle {
+            Some(ref nonces) if nonces.len() == PROOFSIZE => {
+                let mut proof = [0; PROOFSIZE];
+                proof.copy_from_slice(nonces);
+                if cs.target.is_met_by(&proof) {
+                    control.report(1.0);
+                    return SolveStatus::Solved(proof);
+                }
+            }
+            _ => {}
+        }
+    }
+    control.report(1.0);
+    SolveStatus::Exhausted
+}
+
+pub fn solve(cs: CuckooSolve) -> Option<Proof> {
+    solve_trimmed(cs, NTRIMS)
+}
+
+// Same contract as simple_miner::solve, but only the edges that survive
+// trimming are searched for cycles
+pub fn solve_trimmed(cs: CuckooSolve, ntrims: usize) -> Option<Proof> {
+    solve_with(cs, ntrims, &mut SolveControl::new()).proof()
+}
+
+pub fn solve_with(cs: CuckooSolve, ntrims: usize, control: &mut SolveControl) -> SolveStatus {
+    let survivors = match trim_with(&cs, ntrims, |_| false, control) {
+        Ok(survivors) => survivors,
+        Err(status) => return *status,
+    };
+    let mut edges = EdgeGen::new(cs.params, cs.graph_v);
+    let edges = survivors.into_iter().map(|n| (n, edges.edge(n)));
+    search(&cs, edges, control)
+}
+
+#[cfg(test)]
+mod tests {
+    use cuckoo::{verify_params, Params, Variant};
+    use lean_miner;
+    use simple_miner::{self, test_problem, CuckooSolve};
+    use difficulty::Target;
+
+    #[test]
+    fn agrees_with_simple_miner() {
+        let cases = [
+            (Variant::Cuckoo, 0),
+            (Variant::Cuckoo, 1),
+            (Variant::Cuckoo, 2),
+            (Variant::Cuckatoo, 0),
+            (Variant::Cuckatoo, 33),
+            (Variant::Cuckaroo, 7),
+            (Variant::Cuckaroo, 8),
+        ];
+        for &(variant, i) in cases.iter() {
+            let params = Params::new(variant, 16);
+            let cs = test_problem(params, &format!("lean {}", i));
+            let simple = simple_miner::solve(cs.clone());
+            let lean = lean_miner::solve(cs.clone());
+            assert_eq!(simple, lean);
+            if let Some(proof) = lean {
+                assert!(verify_params(params, cs.graph_v, proof, cs.easiness, Target::MAX));
+            }
+        }
+    }
+
+    #[test]
+    fn trimming_keeps_cycle_edges() {
+        let params = Params::new(Variant::Cuckatoo, 16);
+        let cs = CuckooSolve {
+            easiness: params.nedges(),
+            ..test_problem(params, "lean 33")
+        };
+        let survivors = lean_miner::trim(&cs, lean_miner::NTRIMS);
+        assert!(survivors.len() < params.nedges() as usize / 4);
+
+        let proof = lean_miner::solve(cs.clone()).unwrap();
+        assert!(proof.iter().all(|n| survivors.contains(n)));
+    }
+}
@@ -1,6 +1,25 @@
 extern crate blake2;
+#[macro_use]
+extern crate log;
 extern crate rand;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(unix)]
+extern crate signal_hook;
 
+pub mod access_log;
+pub mod admin;
+pub mod client;
+pub mod csprng;
 pub mod cuckoo;
+pub mod difficulty;
+pub mod encoding;
+pub mod gateway;
+pub mod handler;
 pub mod http_server;
+pub mod lean_miner;
+pub mod mean_miner;
+pub mod metrics;
 pub mod simple_miner;
+pub mod solver;
+pub mod verifier;
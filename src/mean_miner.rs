@@ -0,0 +1,246 @@
+use std::mem;
+
+use cuckoo::Edge;
+use cuckoo::EdgeGen;
+use cuckoo::Params;
+use cuckoo::Proof;
+use lean_miner;
+use simple_miner::CuckooSolve;
+use solver::{SolveControl, SolveStatus};
+
+pub const NTRIMS: usize = 32;
+pub const DEFAULT_BUCKET_BITS: u32 = 8;
+pub const DEFAULT_MEMORY_BUDGET: usize = 256 << 20;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    nonce: u32,
+    u: u32,
+    v: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MeanConfig {
+    // Edges are bucketed on the top bucket_bits of the node being trimmed,
+    // so each bucket only needs 2^(edge_bits - bucket_bits) degree counters
+    pub bucket_bits: u32,
+    // Upper bound on the bytes spent on bucketed edges. Graphs that don't
+    // fit are lean-trimmed in place until they do.
+    pub memory_budget: usize,
+    pub ntrims: usize,
+}
+
+impl Default for MeanConfig {
+    fn default() -> MeanConfig {
+        MeanConfig {
+            bucket_bits: DEFAULT_BUCKET_BITS,
+            memory_budget: DEFAULT_MEMORY_BUDGET,
+            ntrims: NTRIMS,
+        }
+    }
+}
+
+// Bytes needed to bucket `nedges` edges: a source and destination buffer
+pub fn bucket_memory(nedges: usize) -> usize {
+    2 * nedges * mem::size_of::<Entry>()
+}
+
+struct Buckets {
+    node_bits: u32,
+    bucket_bits: u32,
+    offsets: Vec<usize>,
+    sorted: Vec<Entry>,
+    degrees: Vec<u8>,
+}
+
+impl Buckets {
+    fn new(params: Params, bucket_bits: u32) -> Buckets {
+        let bucket_bits = if bucket_bits > params.edge_bits {
+            params.edge_bits
+        } else {
+            bucket_bits
+        };
+        Buckets {
+            node_bits: params.edge_bits,
+            bucket_bits,
+            offsets: vec![0; (1 << bucket_bits) + 1],
+            sorted: Vec::new(),
+            degrees: vec![0; 1 << (params.edge_bits - bucket_bits)],
+        }
+    }
+
+    // One round of leaf removal on one side: counting-sort the edges into
+    // buckets by node, then drop edges whose node has degree one
+    fn trim(&mut self, edges: &mut Vec<Entry>, side: usize) {
+        let shift = self.node_bits - self.bucket_bits;
+        let local_mask = (1u32 << shift) - 1;
+        let node = |e: &Entry| if side == 0 { e.u } else { e.v };
+
+        for o in self.offsets.iter_mut() {
+            *o = 0;
+        }
+        for e in edges.iter() {
+            self.offsets[(node(e) >> shift) as usize + 1] += 1;
+        }
+        for b in 1..self.offsets.len() {
+            self.offsets[b] += self.offsets[b - 1];
+        }
+
+        let mut next = self.offsets.clone();
+        self.sorted.clear();
+        self.sorted.resize(
+            edges.len(),
+            Entry {
+                nonce: 0,
+                u: 0,
+                v: 0,
+            },
+        );
+        for e in edges.iter() {
+            let b = (node(e) >> shift) as usize;
+            self.sorted[next[b]] = *e;
+            next[b] += 1;
+        }
+
+        edges.clear();
+        for b in 0..(self.offsets.len() - 1) {
+            let bucket = &self.sorted[self.offsets[b]..self.offsets[b + 1]];
+            for e in bucket {
+                let d = &mut self.degrees[(node(e) & local_mask) as usize];
+                if *d < 2 {
+                    *d += 1;
+                }
+            }
+            for e in bucket {
+                if self.degrees[(node(e) & local_mask) as usize] >= 2 {
+                    edges.push(*e);
+                }
+            }
+            for e in bucket {
+                self.degrees[(node(e) & local_mask) as usize] = 0;
+            }
+        }
+    }
+}
+
+pub fn solve(cs: CuckooSolve) -> Option<Proof> {
+    solve_with_config(cs, &MeanConfig::default())
+}
+
+// Same contract as simple_miner::solve
+pub fn solve_with_config(cs: CuckooSolve, config: &MeanConfig) -> Option<Proof> {
+    solve_with(cs, config, &mut SolveControl::new()).proof()
+}
+
+pub fn solve_with(cs: CuckooSolve, config: &MeanConfig, control: &mut SolveControl) -> SolveStatus {
+    let limit = cs.params.nonce_limit(cs.easiness) as usize;
+    let budget = config.memory_budget;
+
+    let nonces: Vec<u32> = if bucket_memory(limit) <= budget {
+        (0..limit as u32).collect()
+    } else {
+        let fits = |left| bucket_memory(left) <= budget;
+        match lean_miner::trim_with(&cs, config.ntrims, fits, control) {
+            Ok(survivors) => survivors.into_iter().map(|n| n as u32).collect(),
+            Err(status) => return *status,
+        }
+    };
+    if bucket_memory(nonces.len()) > budget {
+        control.report(1.0);
+        return SolveStatus::Exhausted;
+    }
+
+    let mut gen = EdgeGen::new(cs.params, cs.graph_v);
+    let mut edges: Vec<Entry> = nonces
+        .into_iter()
+        .map(|n| {
+            let e = gen.edge(n as i32);
+            Entry {
+                nonce: n,
+                u: e.u as u32,
+                v: e.v as u32,
+            }
+        })
+        .collect();
+
+    let mut buckets = Buckets::new(cs.params, config.bucket_bits);
+    let mut idle_rounds = 0;
+    for round in 0..config.ntrims {
+        if let Some(status) = control.checkpoint(round as f64 / (config.ntrims + 1) as f64) {
+            return status;
+        }
+        let before = edges.len();
+        buckets.trim(&mut edges, round % 2);
+        idle_rounds = if edges.len() == before {
+            idle_rounds + 1
+        } else {
+            0
+        };
+        if idle_rounds == 2 {
+            break;
+        }
+    }
+    edges.sort_by_key(|e| e.nonce);
+
+    let edges = edges.into_iter().map(|e| {
+        (
+            e.nonce as i32,
+            Edge {
+                u: e.u as i32,
+                v: e.v as i32,
+            },
+        )
+    });
+    lean_miner::search(&cs, edges, control)
+}
+
+#[cfg(test)]
+mod tests {
+    use cuckoo::{Params, Variant, PROOFSIZE};
+    use lean_miner;
+    use mean_miner::{self, bucket_memory, MeanConfig};
+    use simple_miner::{test_problem, CuckooSolve};
+
+    fn problem(variant: Variant, i: i32) -> CuckooSolve {
+        test_problem(Params::new(variant, 16), &format!("lean {}", i))
+    }
+
+    #[test]
+    fn agrees_with_lean_miner() {
+        let cases = [
+            (Variant::Cuckoo, 0),
+            (Variant::Cuckatoo, 0),
+            (Variant::Cuckatoo, 33),
+            (Variant::Cuckaroo, 7),
+        ];
+        for &(variant, i) in cases.iter() {
+            let cs = problem(variant, i);
+            assert_eq!(
+                mean_miner::solve(cs.clone()),
+                lean_miner::solve(cs.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn respects_memory_budget() {
+        let cs = problem(Variant::Cuckatoo, 33);
+        let expected = lean_miner::solve(cs.clone());
+        assert!(expected.is_some());
+
+        // Too small for the whole graph, so it has to lean-trim first
+        let config = MeanConfig {
+            bucket_bits: 4,
+            memory_budget: bucket_memory(cs.params.nedges() as usize / 4),
+            ..MeanConfig::default()
+        };
+        assert_eq!(mean_miner::solve_with_config(cs.clone(), &config), expected);
+
+        // Room for fewer edges than a proof has
+        let config = MeanConfig {
+            memory_budget: bucket_memory(PROOFSIZE / 2),
+            ..MeanConfig::default()
+        };
+        assert_eq!(mean_miner::solve_with_config(cs, &config), None);
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+// Upper bounds, in seconds, of the latency histogram buckets
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+#[derive(Debug, Default)]
+pub struct Counter {
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge {
+    value: AtomicI64,
+}
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, v: i64) {
+        self.value.store(v, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+// Counters keyed by the value of a single label
+#[derive(Debug, Default)]
+pub struct LabeledCounter {
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl LabeledCounter {
+    pub fn add(&self, label: &str, n: u64) {
+        *self.values.lock().unwrap().entry(label.to_string()).or_insert(0) += n;
+    }
+
+    pub fn inc(&self, label: &str) {
+        self.add(label, 1);
+    }
+
+    pub fn get(&self, label: &str) -> u64 {
+        *self.values.lock().unwrap().get(label).unwrap_or(&0)
+    }
+}
+
+#[derive(Debug)]
+pub struct Histogram {
+    // Non-cumulative; one more than LATENCY_BUCKETS for +Inf
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: (0..LATENCY_BUCKETS.len() + 1)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, d: Duration) {
+        let secs = d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9;
+        let i = LATENCY_BUCKETS
+            .iter()
+            .position(|b| secs <= *b)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(
+            d.as_secs() * 1_000_000 + d.subsec_micros() as u64,
+            Ordering::Relaxed,
+        );
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+// Everything the gateway counts. One of these is shared by the listener and
+// every connection.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub connections_accepted: Counter,
+    pub connections_active: Gauge,
+    pub challenges_issued: Counter,
+    pub challenges_solved: Counter,
+    pub challenges_expired: Counter,
+    // Keyed by rejection reason
+    pub challenges_failed: LabeledCounter,
+    pub pending_challenges: Gauge,
+    pub verify_latency: Histogram,
+    // Keyed by resource name
+    pub bytes_served: LabeledCounter,
+    pub upstream_latency: Histogram,
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, c: &Counter) {
+    header(out, name, "counter", help);
+    writeln!(out, "{} {}", name, c.get()).unwrap();
+}
+
+fn render_gauge(out: &mut String, name: &str, help: &str, g: &Gauge) {
+    header(out, name, "gauge", help);
+    writeln!(out, "{} {}", name, g.get()).unwrap();
+}
+
+fn render_labeled(out: &mut String, name: &str, label: &str, help: &str, c: &LabeledCounter) {
+    header(out, name, "counter", help);
+    for (k, v) in c.values.lock().unwrap().iter() {
+        let k = k.replace('\\', "\\\\").replace('"', "\\\"");
+        writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, k, v).unwrap();
+    }
+}
+
+fn render_histogram(out: &mut String, name: &str, help: &str, h: &Histogram) {
+    header(out, name, "histogram", help);
+    let mut cumulative = 0;
+    for (i, b) in h.buckets.iter().enumerate() {
+        cumulative += b.load(Ordering::Relaxed);
+        if i < LATENCY_BUCKETS.len() {
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, LATENCY_BUCKETS[i], cumulative).unwrap();
+        } else {
+            writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative).unwrap();
+        }
+    }
+    let sum = h.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+    writeln!(out, "{}_sum {}", name, sum).unwrap();
+    writeln!(out, "{}_count {}", name, h.count()).unwrap();
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Metrics> {
+        Arc::new(Metrics::default())
+    }
+
+    // Prometheus text exposition format, version 0.0.4
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        render_counter(
+            &mut out,
+            "cuckoo_connections_accepted_total",
+            "Connections accepted by the gateway",
+            &self.connections_accepted,
+        );
+        render_gauge(
+            &mut out,
+            "cuckoo_connections_active",
+            "Connections currently being handled",
+            &self.connections_active,
+        );
+        render_counter(
+            &mut out,
+            "cuckoo_challenges_issued_total",
+            "Challenges handed out",
+            &self.challenges_issued,
+        );
+        render_counter(
+            &mut out,
+            "cuckoo_challenges_solved_total",
+            "Challenges answered with a valid proof",
+            &self.challenges_solved,
+        );
+        render_counter(
+            &mut out,
+            "cuckoo_challenges_expired_total",
+            "Challenges dropped unsolved after their TTL",
+            &self.challenges_expired,
+        );
+        render_labeled(
+            &mut out,
+            "cuckoo_challenges_failed_total",
+            "reason",
+            "Rejected submissions by reason",
+            &self.challenges_failed,
+        );
+        render_gauge(
+            &mut out,
+            "cuckoo_pending_challenges",
+            "Challenges issued and not yet solved or expired",
+            &self.pending_challenges,
+        );
+        render_histogram(
+            &mut out,
+            "cuckoo_verify_seconds",
+            "Time spent verifying a proof",
+            &self.verify_latency,
+        );
+        render_labeled(
+            &mut out,
+            "cuckoo_bytes_served_total",
+            "resource",
+            "Response bytes written per resource",
+            &self.bytes_served,
+        );
+        render_histogram(
+            &mut out,
+            "cuckoo_upstream_seconds",
+            "Time spent waiting on the upstream server",
+            &self.upstream_latency,
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::Metrics;
+    use std::time::Duration;
+
+    #[test]
+    fn renders_text_format() {
+        let m = Metrics::new();
+        m.connections_accepted.add(3);
+        m.connections_active.inc();
+        m.challenges_failed.inc("invalid_proof");
+        m.challenges_failed.inc("invalid_proof");
+        m.challenges_failed.inc("expired");
+        m.verify_latency.observe(Duration::new(0, 300_000));
+        m.verify_latency.observe(Duration::new(2, 0));
+
+        let text = m.render();
+        assert!(text.contains("# TYPE cuckoo_connections_accepted_total counter\n"));
+        assert!(text.contains("\ncuckoo_connections_accepted_total 3\n"));
+        assert!(text.contains("\ncuckoo_connections_active 1\n"));
+        assert!(text.contains("cuckoo_challenges_failed_total{reason=\"expired\"} 1\n"));
+        assert!(text.contains("cuckoo_challenges_failed_total{reason=\"invalid_proof\"} 2\n"));
+        assert!(text.contains("cuckoo_verify_seconds_bucket{le=\"0.00025\"} 0\n"));
+        assert!(text.contains("cuckoo_verify_seconds_bucket{le=\"0.0005\"} 1\n"));
+        assert!(text.contains("cuckoo_verify_seconds_bucket{le=\"1\"} 1\n"));
+        assert!(text.contains("cuckoo_verify_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("cuckoo_verify_seconds_sum 2.0003\n"));
+        assert!(text.contains("cuckoo_verify_seconds_count 2\n"));
+    }
+}
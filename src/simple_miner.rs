@@ -1,25 +1,170 @@
 const MAXPATHLEN: usize = 4096;
 
+use std::cmp::max as _max;
 use std::cmp::min as _min;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::ops::Range;
+use std::sync::{Barrier, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
 
+use cuckoo::{self, KeyingScheme, EDGE_BLOCK_SIZE};
 use cuckoo::Edge;
-use cuckoo::NEDGES;
+use cuckoo::EdgeGen;
 use cuckoo::PROOFSIZE;
-use cuckoo::proof_satisfies_difficulty;
-use cuckoo::sipedge;
-use cuckoo::sipnode;
+use cuckoo::Params;
+use cuckoo::Proof;
+use difficulty::Target;
+use lean_miner::{CycleFinder, NTRIMS};
+use solver::{CancelToken, SolveControl, SolveStatus, CHECK_INTERVAL};
 
 #[derive(Clone)]
 pub struct CuckooSolve {
     pub graph_v: [u64; 4],
     pub easiness: i32,
-    pub hash_difficulty: u64,
-    pub cuckoo: Vec<i32>,
+    pub target: Target,
+    pub params: Params,
+}
+
+impl CuckooSolve {
+    // The graph keyed from `header`, with the easiness at `easipct` percent
+    // of the node count and no difficulty target
+    pub fn new(header: &[u8], keying: KeyingScheme, params: Params, easipct: i32) -> CuckooSolve {
+        CuckooSolve {
+            graph_v: cuckoo::header_keys(header, keying),
+            easiness: ((easipct as i64 * params.nnodes() as i64) / 100) as i32,
+            target: Target::MAX,
+            params,
+        }
+    }
+}
+
+// The graphs the solver and verifier tests share
+#[cfg(test)]
+pub fn test_problem(params: Params, header: &str) -> CuckooSolve {
+    CuckooSolve::new(header.as_bytes(), KeyingScheme::Blake2b256, params, 70)
+}
+
+// How an Arena stores its entries
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    // A 32 bit word per entry
+    Plain,
+    // Entries bit-packed to the width of a node index or nonce, about 30%
+    // smaller at the usual sizes
+    Packed,
+}
+
+// Fixed-width unsigned entries packed into 64 bit words
+#[derive(Clone, Debug)]
+struct PackedVec {
+    bits: u32,
+    words: Vec<u64>,
+}
+
+impl PackedVec {
+    fn new() -> PackedVec {
+        PackedVec {
+            bits: 32,
+            words: Vec::new(),
+        }
+    }
+
+    // Zeroes the first `len` entries of `bits` each, reusing the allocation
+    fn reset(&mut self, len: usize, bits: u32) {
+        self.bits = bits;
+        self.words.clear();
+        self.words.resize((len * bits as usize).div_ceil(64), 0);
+    }
+
+    #[inline]
+    fn get(&self, i: usize) -> i32 {
+        if self.bits == 32 {
+            return (self.words[i / 2] >> (i % 2 * 32)) as u32 as i32;
+        }
+        let bit = i * self.bits as usize;
+        let (w, off) = (bit / 64, (bit % 64) as u32);
+        let mut x = self.words[w] >> off;
+        if off + self.bits > 64 {
+            x |= self.words[w + 1] << (64 - off);
+        }
+        (x & ((1 << self.bits) - 1)) as i32
+    }
+
+    #[inline]
+    fn set(&mut self, i: usize, value: i32) {
+        if self.bits == 32 {
+            let shift = i % 2 * 32;
+            let w = &mut self.words[i / 2];
+            *w = (*w & !(0xffff_ffff << shift)) | ((value as u32 as u64) << shift);
+            return;
+        }
+        let mask = (1u64 << self.bits) - 1;
+        let x = value as u64 & mask;
+        let bit = i * self.bits as usize;
+        let (w, off) = (bit / 64, (bit % 64) as u32);
+        self.words[w] = (self.words[w] & !(mask << off)) | (x << off);
+        if off + self.bits > 64 {
+            let high = 64 - off;
+            self.words[w + 1] = (self.words[w + 1] & !(mask >> high)) | (x >> high);
+        }
+    }
+}
+
+fn bits_for(max: i32) -> u32 {
+    _max(1, 32 - (max as u32).leading_zeros())
+}
+
+// Working memory of the simple miner: the cuckoo forest, mapping each node
+// to its parent, and the nonce of the edge to that parent. Keep one around
+// and pass it to solve_in to reuse the allocation across solves.
+#[derive(Clone, Debug)]
+pub struct Arena {
+    layout: Layout,
+    cuckoo: PackedVec,
+    links: PackedVec,
+}
+
+impl Arena {
+    pub fn new(layout: Layout) -> Arena {
+        Arena {
+            layout,
+            cuckoo: PackedVec::new(),
+            links: PackedVec::new(),
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    // Bytes used by the tables, once sized for a solve
+    pub fn bytes(&self) -> usize {
+        (self.cuckoo.words.capacity() + self.links.words.capacity()) * 8
+    }
+
+    // Sizes and clears the tables for a graph
+    fn prepare(&mut self, params: Params, limit: i32) {
+        let len = 1 + params.nnodes() as usize;
+        let (node_bits, nonce_bits) = match self.layout {
+            Layout::Plain => (32, 32),
+            Layout::Packed => (bits_for(params.nnodes() - 1), bits_for(limit - 1)),
+        };
+        self.cuckoo.reset(len, node_bits);
+        self.links.reset(len, nonce_bits);
+    }
+}
+
+impl Default for Arena {
+    fn default() -> Arena {
+        Arena::new(Layout::Plain)
+    }
 }
 
 // Refactor sometime
-pub fn path(v: &CuckooSolve, mut u: i32, us: &mut [i32; MAXPATHLEN]) -> Option<usize> {
+pub fn path(arena: &Arena, mut u: i32, us: &mut [i32; MAXPATHLEN]) -> Option<usize> {
     let mut nu: usize = 0;
     while u != 0 {
         nu += 1;
@@ -28,135 +173,709 @@ pub fn path(v: &CuckooSolve, mut u: i32, us: &mut [i32; MAXPATHLEN]) -> Option<u
                 nu -= 1;
             }
             if nu <= 0 {
-                println!("maximum path length exceeded");
+                warn!("maximum path length exceeded max_len={}", MAXPATHLEN);
             } else {
-                println!("illegal {}-cycle", (MAXPATHLEN - nu));
+                warn!("illegal cycle len={}", MAXPATHLEN - nu);
             }
             return Option::None;
         }
         us[nu] = u;
-        u = v.cuckoo[u as usize];
+        u = arena.cuckoo.get(u as usize);
     }
 
     return Some(nu);
 }
 
-pub fn solution(
-    v: &CuckooSolve,
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FoundCycle {
+    pub len: usize,
+    // Sorted nonces of the cycle's edges
+    pub nonces: Vec<i32>,
+    // Whether this is a PROOFSIZE-cycle whose hash meets the difficulty
+    pub meets_difficulty: bool,
+    // Nonce of the edge that closed the cycle
+    pub found_at: i32,
+}
+
+impl FoundCycle {
+    pub fn proof(&self) -> Option<Proof> {
+        if self.nonces.len() != PROOFSIZE {
+            return None;
+        }
+        let mut proof = [0; PROOFSIZE];
+        proof.copy_from_slice(&self.nonces);
+        Some(proof)
+    }
+}
+
+enum Step {
+    Cycle(FoundCycle),
+    Stopped(SolveStatus),
+    Done,
+}
+
+// Walks the graph with the simple miner, yielding every cycle it closes
+pub struct Cycles {
+    cs: CuckooSolve,
+    edges: EdgeGen,
+    nonce: i32,
+    limit: i32,
+    // Skip recovering nonces of cycles that can't be proofs
+    only_proofs: bool,
+    // Also holds the nonce of each forest edge, so a cycle's nonces come
+    // straight off its paths
+    arena: Arena,
     us: [i32; MAXPATHLEN],
-    mut nu: i32,
     vs: [i32; MAXPATHLEN],
-    mut nv: i32,
-) -> Option<[i32; PROOFSIZE]> {
-    let mut cycle: HashSet<Edge> = HashSet::new();
+}
 
-    cycle.insert(Edge {
-        u: us[0] as i32,
-        v: (vs[0] - NEDGES) as i32,
-    });
-    while nu != 0 {
-        nu -= 1;
-        cycle.insert(Edge {
-            u: us[((nu + 1) & !1) as usize],
-            v: us[(nu | 1) as usize] - NEDGES,
-        });
+// Sorted nonces of the cycle closed by `nonce` through us[0..nu] and
+// vs[0..nv]
+fn path_nonces(links: &PackedVec, nonce: i32, us: &[i32], nu: usize, vs: &[i32], nv: usize) -> Vec<i32> {
+    let mut nonces = Vec::with_capacity(nu + nv + 1);
+    nonces.push(nonce);
+    nonces.extend(us[..nu].iter().map(|&u| links.get(u as usize)));
+    nonces.extend(vs[..nv].iter().map(|&v| links.get(v as usize)));
+    nonces.sort();
+    nonces
+}
+
+pub fn cycles(cs: CuckooSolve) -> Cycles {
+    Cycles::new(cs, false, Arena::default())
+}
+
+impl Cycles {
+    fn new(cs: CuckooSolve, only_proofs: bool, mut arena: Arena) -> Cycles {
+        let limit = cs.params.nonce_limit(cs.easiness);
+        arena.prepare(cs.params, limit);
+        Cycles {
+            edges: EdgeGen::new(cs.params, cs.graph_v),
+            nonce: 0,
+            limit,
+            only_proofs,
+            arena,
+            cs,
+            us: [0; MAXPATHLEN],
+            vs: [0; MAXPATHLEN],
+        }
     }
-    while nv != 0 {
-        nv -= 1;
-        cycle.insert(Edge {
-            u: vs[(nv | 1) as usize],
-            v: vs[((nv + 1) & !1) as usize] - NEDGES,
-        });
+
+    fn advance(&mut self, control: &mut SolveControl) -> Step {
+        let nedges = self.cs.params.nedges();
+        let us = &mut self.us;
+        let vs = &mut self.vs;
+        let cs = &self.cs;
+        let arena = &mut self.arena;
+
+        while self.nonce < self.limit {
+            let nonce = self.nonce;
+            self.nonce += 1;
+            if (nonce as usize).is_multiple_of(CHECK_INTERVAL) {
+                if let Some(status) = control.checkpoint(nonce as f64 / self.limit as f64) {
+                    return Step::Stopped(status);
+                }
+            }
+
+            let e = self.edges.edge(nonce);
+            us[0] = e.u;
+            vs[0] = nedges + e.v;
+
+            let u = arena.cuckoo.get(us[0] as usize);
+            let v = arena.cuckoo.get(vs[0] as usize);
+
+            if u == vs[0] || v == us[0] {
+                continue;
+            }
+
+            let nu_raw = path(arena, u, us);
+            let nv_raw = path(arena, v, vs);
+
+            if nu_raw.is_none() || nv_raw.is_none() {
+                continue;
+            }
+
+            let mut nu: i32 = nu_raw.unwrap() as i32;
+            let mut nv: i32 = nv_raw.unwrap() as i32;
+
+            if us[nu as usize] == vs[nv as usize] {
+                let min = _min(nu, nv);
+
+                nu -= min;
+                nv -= min;
+
+                while us[nu as usize] != vs[nv as usize] {
+                    nu += 1;
+                    nv += 1;
+                }
+
+                let len = (nu + nv + 1) as usize;
+                control.cycle_found();
+                if self.only_proofs && len != PROOFSIZE {
+                    continue;
+                }
+
+                let nonces = path_nonces(&arena.links, nonce, us, nu as usize, vs, nv as usize);
+                let mut found = FoundCycle {
+                    len,
+                    nonces,
+                    meets_difficulty: false,
+                    found_at: nonce,
+                };
+                found.meets_difficulty = match found.proof() {
+                    Some(proof) => len == PROOFSIZE
+                        && cs.target.is_met_by(&proof),
+                    None => false,
+                };
+                return Step::Cycle(found);
+            }
+            if nu < nv {
+                while nu != 0 {
+                    nu -= 1;
+                    let (node, next) = (us[nu as usize] as usize, us[(nu + 1) as usize] as usize);
+                    arena.cuckoo.set(next, node as i32);
+                    let link = arena.links.get(node);
+                    arena.links.set(next, link);
+                }
+                arena.cuckoo.set(us[0] as usize, vs[0]);
+                arena.links.set(us[0] as usize, nonce);
+            } else {
+                while nv != 0 {
+                    nv -= 1;
+                    let (node, next) = (vs[nv as usize] as usize, vs[(nv + 1) as usize] as usize);
+                    arena.cuckoo.set(next, node as i32);
+                    let link = arena.links.get(node);
+                    arena.links.set(next, link);
+                }
+                arena.cuckoo.set(vs[0] as usize, us[0]);
+                arena.links.set(vs[0] as usize, nonce);
+            }
+        }
+        Step::Done
     }
+}
+
+impl Iterator for Cycles {
+    type Item = FoundCycle;
 
-    let mut new_proof = [0; PROOFSIZE];
-    let mut n = 0;
-    for nonce in 0..v.easiness {
-        let e = sipedge(v.graph_v, nonce);
-        if cycle.contains(&e) {
-            new_proof[n] = nonce;
-            n += 1;
+    fn next(&mut self) -> Option<FoundCycle> {
+        match self.advance(&mut SolveControl::new()) {
+            Step::Cycle(found) => Some(found),
+            Step::Stopped(_) | Step::Done => None,
         }
     }
+}
+
+pub fn solve(cs: CuckooSolve) -> Option<[i32; PROOFSIZE]> {
+    solve_with(cs, &mut SolveControl::new()).proof()
+}
 
-    if n != PROOFSIZE {
-        println!("Only recovered {:?} nonces", n);
-        return None;
-    } else if proof_satisfies_difficulty(&new_proof, v.hash_difficulty) {
-        return Some(new_proof);
-    } else {
-        return None;
+pub fn solve_with(cs: CuckooSolve, control: &mut SolveControl) -> SolveStatus {
+    solve_in(cs, &mut Arena::default(), control)
+}
+
+// Like solve_with, using and keeping `arena`'s memory
+pub fn solve_in(cs: CuckooSolve, arena: &mut Arena, control: &mut SolveControl) -> SolveStatus {
+    let layout = arena.layout;
+    let mut cycles = Cycles::new(cs, true, mem::replace(arena, Arena::new(layout)));
+    let status = search(&mut cycles, control);
+    *arena = cycles.arena;
+    status
+}
+
+fn search(cycles: &mut Cycles, control: &mut SolveControl) -> SolveStatus {
+    loop {
+        match cycles.advance(control) {
+            Step::Cycle(found) => {
+                if found.meets_difficulty {
+                    control.report(1.0);
+                    return SolveStatus::Solved(found.proof().unwrap());
+                } else if found.nonces.len() != PROOFSIZE {
+                    debug!(
+                        "cycle rejected nonces={} expected={}",
+                        found.nonces.len(),
+                        PROOFSIZE
+                    );
+                } else {
+                    debug!(
+                        "cycle rejected reason=difficulty found_at_pct={}",
+                        (found.found_at as i64 * 100) / cycles.limit as i64
+                    );
+                }
+            }
+            Step::Stopped(status) => return status,
+            Step::Done => {
+                control.report(1.0);
+                return SolveStatus::Exhausted;
+            }
+        }
     }
 }
 
-pub fn solve(mut cs: CuckooSolve) -> Option<[i32; PROOFSIZE]> {
-    let mut us: [i32; MAXPATHLEN] = [0; MAXPATHLEN];
-    let mut vs: [i32; MAXPATHLEN] = [0; MAXPATHLEN];
-    for nonce in 0..cs.easiness {
-        us[0] = sipnode(cs.graph_v, nonce, 0);
-        vs[0] = NEDGES + sipnode(cs.graph_v, nonce, 1);
+#[derive(Clone, Copy, Debug)]
+pub struct ParallelConfig {
+    pub threads: usize,
+    pub ntrims: usize,
+}
 
-        let u = cs.cuckoo[us[0] as usize];
-        let v = cs.cuckoo[vs[0] as usize];
+impl Default for ParallelConfig {
+    fn default() -> ParallelConfig {
+        ParallelConfig {
+            threads: thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            ntrims: NTRIMS,
+        }
+    }
+}
 
-        if u == vs[0] || v == us[0] {
-            continue;
+struct AtomicBitmap {
+    bits: Vec<AtomicU64>,
+}
+
+impl AtomicBitmap {
+    fn new(len: usize, value: u64) -> AtomicBitmap {
+        AtomicBitmap {
+            bits: (0..len.div_ceil(64)).map(|_| AtomicU64::new(value)).collect(),
         }
+    }
 
-        let nu_raw = path(&cs, u, &mut us);
-        let nv_raw = path(&cs, v, &mut vs);
+    #[inline]
+    fn get(&self, i: usize) -> bool {
+        (self.bits[i >> 6].load(Ordering::Relaxed) >> (i & 63)) & 1 != 0
+    }
 
-        if nu_raw.is_none() || nv_raw.is_none() {
-            continue;
+    // Returns whether the bit was already set
+    #[inline]
+    fn set(&self, i: usize) -> bool {
+        let bit = 1 << (i & 63);
+        self.bits[i >> 6].fetch_or(bit, Ordering::Relaxed) & bit != 0
+    }
+
+    #[inline]
+    fn reset(&self, i: usize) {
+        self.bits[i >> 6].fetch_and(!(1 << (i & 63)), Ordering::Relaxed);
+    }
+
+    fn clear(&self, words: Range<usize>) {
+        for w in &self.bits[words] {
+            w.store(0, Ordering::Relaxed);
         }
+    }
+}
 
-        let mut nu: i32 = nu_raw.unwrap() as i32;
-        let mut nv: i32 = nv_raw.unwrap() as i32;
+// Splits 0..len into one range per thread, aligned to siphash blocks so
+// Cuckaroo workers never share a block
+fn chunks(len: usize, threads: usize) -> Vec<Range<usize>> {
+    let per = len.div_ceil(threads);
+    let per = per.div_ceil(EDGE_BLOCK_SIZE) * EDGE_BLOCK_SIZE;
+    (0..threads)
+        .map(|t| _min(t * per, len).._min((t + 1) * per, len))
+        .filter(|r| r.start < r.end)
+        .collect()
+}
+
+// SolveControl::should_stop for worker threads, which can only share the
+// cancel token and deadline
+fn should_stop(cancel: &CancelToken, deadline: Option<Instant>) -> bool {
+    cancel.is_cancelled() || deadline.is_some_and(|d| Instant::now() >= d)
+}
 
-        if us[nu as usize] == vs[nv as usize] {
-            let min = _min(nu, nv);
+// One parallel solve's state, shared by the thread coordinating it and
+// the workers. The workers run in lock step with the coordinator, meeting
+// at `phase` between each step of a round, and the coordinator sets `stop`
+// before one of those meetings to send them home.
+struct Shared<'a> {
+    cs: &'a CuckooSolve,
+    ntrims: usize,
+    // Edges the trimming starts from, one alive bit each
+    limit: usize,
+    alive: AtomicBitmap,
+    once: AtomicBitmap,
+    twice: AtomicBitmap,
+    edge_ranges: Vec<Range<usize>>,
+    word_ranges: Vec<Range<usize>>,
+    cancel: CancelToken,
+    deadline: Option<Instant>,
+    phase: Barrier,
+    stop: AtomicBool,
+    // Components holding a cycle, smallest first, for the workers to take
+    // from the back
+    components: Mutex<Vec<Vec<(i32, Edge)>>>,
+    found: AtomicBool,
+    result: Mutex<Option<Proof>>,
+}
+
+impl<'a> Shared<'a> {
+    // Waits at a meeting the workers check `stop` after
+    fn halt(&self, status: SolveStatus) -> Option<SolveStatus> {
+        self.stop.store(true, Ordering::SeqCst);
+        self.phase.wait();
+        Some(status)
+    }
 
-            nu -= min;
-            nv -= min;
+    fn stopping(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+}
 
-            while us[nu as usize] != vs[nv as usize] {
-                nu += 1;
-                nv += 1;
+// A worker's part of leaf trimming and then the cycle search. Work cut
+// short by cancellation or the deadline still meets the others at `phase`.
+fn work(shared: &Shared, t: usize) {
+    let cs = shared.cs;
+    let (alive, once, twice) = (&shared.alive, &shared.once, &shared.twice);
+    let edges_in = |range: &Range<usize>, f: &mut dyn FnMut(usize, Edge)| {
+        let mut edges = EdgeGen::new(cs.params, cs.graph_v);
+        for nonce in range.clone() {
+            if (nonce - range.start).is_multiple_of(CHECK_INTERVAL) && should_stop(&shared.cancel, shared.deadline) {
+                return;
+            }
+            if alive.get(nonce) {
+                f(nonce, edges.edge(nonce as i32));
             }
+        }
+    };
 
-            let len = nu + nv + 1;
-            /*println!(
-                "{}-cycle found at {}%",
-                len,
-                (nonce * 100) / cs.easiness,
-            );*/
-            if len == (PROOFSIZE as i32) {
-                let sol = solution(&cs, us, nu, vs, nv);
-                if sol.is_some() {
-                    return sol;
-                } else {
-                    println!(
-                        "Found a sol at {}%, but it did not satisfy the difficulty",
-                        (nonce * 100) / cs.easiness,
-                    );
+    for round in 0..shared.ntrims {
+        let side = |e: Edge| {
+            if round % 2 == 0 {
+                e.u as usize
+            } else {
+                e.v as usize
+            }
+        };
+        shared.phase.wait();
+        if shared.stopping() {
+            return;
+        }
+        if let Some(words) = shared.word_ranges.get(t) {
+            once.clear(words.clone());
+            twice.clear(words.clone());
+        }
+        shared.phase.wait();
+        if let Some(range) = shared.edge_ranges.get(t) {
+            edges_in(range, &mut |_, e| {
+                let node = side(e);
+                if once.set(node) {
+                    twice.set(node);
+                }
+            });
+        }
+        shared.phase.wait();
+        // The coordinator decides whether the counts are complete
+        shared.phase.wait();
+        if shared.stopping() {
+            return;
+        }
+        if let Some(range) = shared.edge_ranges.get(t) {
+            edges_in(range, &mut |nonce, e| {
+                if !twice.get(side(e)) {
+                    alive.reset(nonce);
+                }
+            });
+        }
+        shared.phase.wait();
+    }
+
+    shared.phase.wait();
+    if shared.stopping() {
+        return;
+    }
+    loop {
+        let component = match shared.components.lock().unwrap().pop() {
+            Some(component) => component,
+            None => return,
+        };
+        let mut finder = CycleFinder::new(cs.params);
+        for (i, (nonce, e)) in component.into_iter().enumerate() {
+            if shared.found.load(Ordering::Relaxed) || shared.cancel.is_cancelled() {
+                return;
+            }
+            if i % CHECK_INTERVAL == 0 && should_stop(&shared.cancel, shared.deadline) {
+                return;
+            }
+            match finder.add_edge(nonce, e) {
+                Some(ref nonces) if nonces.len() == PROOFSIZE => {
+                    let mut proof = [0; PROOFSIZE];
+                    proof.copy_from_slice(nonces);
+                    if cs.target.is_met_by(&proof) && !shared.found.swap(true, Ordering::Relaxed) {
+                        *shared.result.lock().unwrap() = Some(proof);
+                        return;
+                    }
                 }
+                _ => {}
             }
+        }
+    }
+}
 
-            continue;
+// Steps the workers through the trimming rounds, reporting progress and
+// stopping them when cancelled or out of time, then hands them the
+// components that may hold a cycle
+fn coordinate(shared: &Shared, control: &mut SolveControl) -> Option<SolveStatus> {
+    let ntrims = shared.ntrims;
+    for round in 0..ntrims {
+        if let Some(status) = control.checkpoint(round as f64 / (ntrims + 1) as f64) {
+            return shared.halt(status);
         }
-        if nu < nv {
-            while nu != 0 {
-                nu -= 1;
-                cs.cuckoo[us[(nu + 1) as usize] as usize] = us[nu as usize];
+        shared.phase.wait();
+        shared.phase.wait();
+        shared.phase.wait();
+        // A worker that stopped early leaves the counts short, so don't
+        // trim with them
+        if let Some(status) = control.should_stop() {
+            return shared.halt(status);
+        }
+        shared.phase.wait();
+        shared.phase.wait();
+        if let Some(status) = control.should_stop() {
+            return shared.halt(status);
+        }
+    }
+
+    let cs = shared.cs;
+    let nedges = cs.params.nedges();
+    let mut edges = EdgeGen::new(cs.params, cs.graph_v);
+    let survivors: Vec<(i32, Edge)> = (0..shared.limit)
+        .filter(|n| shared.alive.get(*n))
+        .map(|n| (n as i32, edges.edge(n as i32)))
+        .collect();
+
+    let mut parents = HashMap::new();
+    for &(_, e) in survivors.iter() {
+        let ru = find(&mut parents, e.u);
+        let rv = find(&mut parents, nedges + e.v);
+        parents.insert(ru, rv);
+    }
+    let mut components: HashMap<i32, Vec<(i32, Edge)>> = HashMap::new();
+    for &(n, e) in survivors.iter() {
+        let root = find(&mut parents, e.u);
+        components.entry(root).or_default().push((n, e));
+    }
+
+    // Only components with at least as many edges as nodes hold a cycle
+    let mut work: Vec<Vec<(i32, Edge)>> = components
+        .into_values()
+        .filter(|c| {
+            let mut nodes = HashSet::new();
+            for &(_, e) in c.iter() {
+                nodes.insert(e.u);
+                nodes.insert(nedges + e.v);
+            }
+            c.len() >= nodes.len() && c.len() >= PROOFSIZE
+        })
+        .collect();
+    work.sort_by_key(|c| c.len());
+    *shared.components.lock().unwrap() = work;
+    shared.phase.wait();
+    None
+}
+
+fn find(parents: &mut HashMap<i32, i32>, mut x: i32) -> i32 {
+    loop {
+        let p = *parents.entry(x).or_insert(x);
+        if p == x {
+            return x;
+        }
+        let gp = *parents.entry(p).or_insert(p);
+        parents.insert(x, gp);
+        x = p;
+    }
+}
+
+// Trims in parallel, then searches the connected components that still
+// contain a cycle, on one set of worker threads started per solve. The
+// first worker to find a proof stops the others.
+pub fn solve_parallel(cs: CuckooSolve, config: &ParallelConfig) -> Option<Proof> {
+    solve_parallel_with(cs, config, &mut SolveControl::new()).proof()
+}
+
+pub fn solve_parallel_with(
+    cs: CuckooSolve,
+    config: &ParallelConfig,
+    control: &mut SolveControl,
+) -> SolveStatus {
+    let threads = if config.threads == 0 { 1 } else { config.threads };
+    let limit = cs.params.nonce_limit(cs.easiness) as usize;
+    let nodes = cs.params.nedges() as usize;
+    let once = AtomicBitmap::new(nodes, 0);
+    let shared = Shared {
+        cs: &cs,
+        ntrims: config.ntrims,
+        limit,
+        alive: AtomicBitmap::new(limit, !0),
+        twice: AtomicBitmap::new(nodes, 0),
+        edge_ranges: chunks(limit, threads),
+        word_ranges: chunks(once.bits.len(), threads),
+        once,
+        cancel: control.cancel_token(),
+        deadline: control.deadline(),
+        // The workers and the coordinator
+        phase: Barrier::new(threads + 1),
+        stop: AtomicBool::new(false),
+        components: Mutex::new(Vec::new()),
+        found: AtomicBool::new(false),
+        result: Mutex::new(None),
+    };
+
+    let stopped = thread::scope(|scope| {
+        for t in 0..threads {
+            let shared = &shared;
+            scope.spawn(move || work(shared, t));
+        }
+        coordinate(&shared, control)
+    });
+    if let Some(status) = stopped {
+        return status;
+    }
+
+    let proof = *shared.result.lock().unwrap();
+    match proof {
+        Some(proof) => {
+            control.report(1.0);
+            SolveStatus::Solved(proof)
+        }
+        None => match control.should_stop() {
+            Some(status) => status,
+            None => {
+                control.report(1.0);
+                SolveStatus::Exhausted
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cuckoo::{verify_params, EdgeGen, Params, Proof, Variant};
+    use std::collections::HashSet;
+    use lean_miner;
+    use simple_miner::{cycles, solve, solve_in, solve_parallel, test_problem, Arena, CuckooSolve, Layout,
+                       PackedVec, ParallelConfig};
+    use solver::SolveControl;
+    use difficulty::Target;
+
+    fn first_solution(params: Params) -> (CuckooSolve, Proof) {
+        for i in 0..1000 {
+            let cs = test_problem(params, &format!("header {}", i));
+            if let Some(proof) = solve(cs.clone()) {
+                return (cs, proof);
+            }
+        }
+        panic!("no solution for {}", params);
+    }
+
+    #[test]
+    fn solves_and_verifies_each_variant() {
+        for variant in [Variant::Cuckoo, Variant::Cuckatoo, Variant::Cuckaroo].iter() {
+            let params = Params::new(*variant, 16);
+            let (cs, proof) = first_solution(params);
+            let (v, easiness) = (cs.graph_v, cs.easiness);
+            assert!(verify_params(params, v, proof, easiness, Target::MAX));
+
+            let mut bad = proof;
+            bad[3] += 1;
+            assert!(!verify_params(params, v, bad, easiness, Target::MAX));
+
+            // Cuckoo and Cuckatoo share an edge function, Cuckaroo does not
+            let other = if *variant == Variant::Cuckaroo {
+                Variant::Cuckatoo
+            } else {
+                Variant::Cuckaroo
+            };
+            assert!(!verify_params(Params::new(other, 16), v, proof, easiness, Target::MAX));
+        }
+    }
+
+    #[test]
+    fn parallel_agrees_with_lean_miner() {
+        let config = ParallelConfig {
+            threads: 3,
+            ..ParallelConfig::default()
+        };
+        for &(variant, i) in [(Variant::Cuckoo, 0), (Variant::Cuckatoo, 33), (Variant::Cuckaroo, 7)].iter() {
+            let params = Params::new(variant, 16);
+            let cs = test_problem(params, &format!("lean {}", i));
+            // With several cycles in a graph, which one a thread reaches
+            // first is down to timing, so check both proofs are among the
+            // graph's cycles
+            let proofs: HashSet<Proof> = cycles(cs.clone()).filter_map(|c| c.proof()).collect();
+            let lean = lean_miner::solve(cs.clone()).unwrap();
+            assert!(proofs.contains(&lean));
+            let proof = solve_parallel(cs.clone(), &config).unwrap();
+            assert!(proofs.contains(&proof));
+            assert!(verify_params(params, cs.graph_v, proof, cs.easiness, cs.target));
+        }
+    }
+
+    #[test]
+    fn cycles_yields_every_cycle() {
+        let params = Params::new(Variant::Cuckatoo, 16);
+        let cs = test_problem(params, "lean 33");
+
+        let all: Vec<_> = cycles(cs.clone()).collect();
+        assert!(all.len() > 1);
+        for c in all.iter() {
+            assert_eq!(c.len % 2, 0);
+            assert_eq!(c.nonces.len(), c.len);
+            assert!(c.nonces.windows(2).all(|w| w[0] < w[1]));
+            assert!(c.nonces.contains(&c.found_at));
+        }
+
+        let first = all.iter().find(|c| c.meets_difficulty).unwrap();
+        assert_eq!(first.proof(), solve(cs.clone()));
+        assert!(verify_params(params, cs.graph_v, first.proof().unwrap(), cs.easiness, Target::MAX));
+    }
+
+    #[test]
+    fn cycle_nonces_match_a_rescan() {
+        for variant in [Variant::Cuckoo, Variant::Cuckatoo, Variant::Cuckaroo].iter() {
+            let params = Params::new(*variant, 16);
+            let cs = test_problem(params, "lean 33");
+            let mut edges = EdgeGen::new(params, cs.graph_v);
+            for c in cycles(cs.clone()).take(16) {
+                // What hashing every nonce again finds
+                let cycle: HashSet<_> = c.nonces.iter().map(|&n| edges.edge(n)).collect();
+                let rescanned: Vec<_> = (0..params.nonce_limit(cs.easiness))
+                    .filter(|&n| cycle.contains(&edges.edge(n)))
+                    .collect();
+                assert_eq!(c.nonces, rescanned);
+            }
+        }
+    }
+
+    #[test]
+    fn packed_entries_round_trip() {
+        for &bits in [1, 17, 23, 32].iter() {
+            let mut v = PackedVec::new();
+            v.reset(100, bits);
+            let max = ((1u64 << bits) - 1) as i32;
+            for i in 0..100 {
+                v.set(i, (i as i32 * 7919) & max);
+            }
+            v.set(50, max);
+            v.set(50, 3 & max);
+            for i in 0..100 {
+                let want = if i == 50 { 3 & max } else { (i as i32 * 7919) & max };
+                assert_eq!(v.get(i), want, "bits={} i={}", bits, i);
             }
-            cs.cuckoo[us[0] as usize] = vs[0];
-        } else {
-            while nv != 0 {
-                nv -= 1;
-                cs.cuckoo[vs[(nv + 1) as usize] as usize] = vs[nv as usize];
+        }
+    }
+
+    #[test]
+    fn packed_arena_solves_like_plain() {
+        let mut plain = Arena::default();
+        let mut packed = Arena::new(Layout::Packed);
+        // Reused across sizes and variants
+        for &(variant, bits) in [(Variant::Cuckatoo, 16), (Variant::Cuckoo, 12), (Variant::Cuckaroo, 16)].iter() {
+            let params = Params::new(variant, bits);
+            for i in 0..4 {
+                let cs = test_problem(params, &format!("lean {}", i));
+                let want = solve(cs.clone());
+                assert_eq!(solve_in(cs.clone(), &mut plain, &mut SolveControl::new()).proof(), want);
+                assert_eq!(solve_in(cs, &mut packed, &mut SolveControl::new()).proof(), want);
             }
-            cs.cuckoo[vs[0] as usize] = us[0];
         }
+        assert!(packed.bytes() < plain.bytes());
+        assert_eq!(packed.layout(), Layout::Packed);
     }
-    return None;
 }
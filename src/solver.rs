@@ -0,0 +1,215 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use cuckoo::Proof;
+
+// How many edges a solver processes between checkpoints
+pub const CHECK_INTERVAL: usize = 1 << 14;
+
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    // Share of the solver's work done so far, from 0 to 1
+    pub fraction: f64,
+    // Cycles of any length closed so far
+    pub cycles_found: usize,
+    pub elapsed: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SolveStatus {
+    Solved(Proof),
+    // Every edge was searched without finding a proof
+    Exhausted,
+    Cancelled,
+    TimedOut,
+}
+
+impl SolveStatus {
+    pub fn proof(self) -> Option<Proof> {
+        match self {
+            SolveStatus::Solved(proof) => Some(proof),
+            _ => None,
+        }
+    }
+}
+
+type ProgressFn<'a> = Box<dyn FnMut(&Progress) + 'a>;
+
+// Passed to the *_with solvers to stop them early and watch them work
+pub struct SolveControl<'a> {
+    cancel: CancelToken,
+    deadline: Option<Instant>,
+    progress: Option<ProgressFn<'a>>,
+    started: Instant,
+    cycles_found: usize,
+}
+
+impl<'a> SolveControl<'a> {
+    pub fn new() -> SolveControl<'a> {
+        SolveControl {
+            cancel: CancelToken::new(),
+            deadline: None,
+            progress: None,
+            started: Instant::now(),
+            cycles_found: 0,
+        }
+    }
+
+    pub fn with_cancel(mut self, cancel: CancelToken) -> SolveControl<'a> {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn with_deadline(mut self, deadline: Instant) -> SolveControl<'a> {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> SolveControl<'a> {
+        let deadline = Instant::now() + timeout;
+        self.with_deadline(deadline)
+    }
+
+    pub fn on_progress<F: FnMut(&Progress) + 'a>(mut self, f: F) -> SolveControl<'a> {
+        self.progress = Some(Box::new(f));
+        self
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn cycles_found(&self) -> usize {
+        self.cycles_found
+    }
+
+    pub fn cycle_found(&mut self) {
+        self.cycles_found += 1;
+    }
+
+    // Reports progress, then says whether the solver has to stop
+    pub fn checkpoint(&mut self, fraction: f64) -> Option<SolveStatus> {
+        self.report(fraction);
+        self.should_stop()
+    }
+
+    pub fn should_stop(&self) -> Option<SolveStatus> {
+        if self.cancel.is_cancelled() {
+            return Some(SolveStatus::Cancelled);
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Some(SolveStatus::TimedOut),
+            _ => None,
+        }
+    }
+
+    pub fn report(&mut self, fraction: f64) {
+        let progress = Progress {
+            fraction,
+            cycles_found: self.cycles_found,
+            elapsed: self.started.elapsed(),
+        };
+        if let Some(ref mut f) = self.progress {
+            f(&progress);
+        }
+    }
+}
+
+impl<'a> Default for SolveControl<'a> {
+    fn default() -> SolveControl<'a> {
+        SolveControl::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cuckoo::{KeyingScheme, Params, Variant};
+    use lean_miner;
+    use simple_miner::{self, CuckooSolve, ParallelConfig};
+    use solver::{CancelToken, SolveControl, SolveStatus};
+    use difficulty::Target;
+    use std::time::Duration;
+
+    fn problem() -> CuckooSolve {
+        CuckooSolve {
+            // Nothing hashes below zero, so every cycle is rejected
+            target: Target(0),
+            ..CuckooSolve::new(b"solver", KeyingScheme::Legacy, Params::new(Variant::Cuckoo, 16), 70)
+        }
+    }
+
+    #[test]
+    fn reports_progress_until_exhausted() {
+        let mut reports = Vec::new();
+        let status = {
+            let mut control = SolveControl::new().on_progress(|p| reports.push(*p));
+            simple_miner::solve_with(problem(), &mut control)
+        };
+        assert_eq!(status, SolveStatus::Exhausted);
+
+        assert!(reports.len() > 2);
+        assert!(reports.windows(2).all(|w| w[0].fraction <= w[1].fraction));
+        let last = reports.last().unwrap();
+        assert_eq!(last.fraction, 1.0);
+        assert!(last.cycles_found > 0);
+    }
+
+    #[test]
+    fn stops_when_cancelled_or_late() {
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let mut control = SolveControl::new().with_cancel(cancel);
+        assert_eq!(
+            simple_miner::solve_with(problem(), &mut control),
+            SolveStatus::Cancelled
+        );
+
+        let mut control = SolveControl::new().with_timeout(Duration::new(0, 0));
+        assert_eq!(
+            lean_miner::solve_with(problem(), lean_miner::NTRIMS, &mut control),
+            SolveStatus::TimedOut
+        );
+
+        // The parallel workers have to be let go as well
+        let config = ParallelConfig {
+            threads: 3,
+            ..ParallelConfig::default()
+        };
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let mut control = SolveControl::new().with_cancel(cancel);
+        assert_eq!(
+            simple_miner::solve_parallel_with(problem(), &config, &mut control),
+            SolveStatus::Cancelled
+        );
+        let mut control = SolveControl::new().with_timeout(Duration::new(0, 0));
+        assert_eq!(
+            simple_miner::solve_parallel_with(problem(), &config, &mut control),
+            SolveStatus::TimedOut
+        );
+    }
+}
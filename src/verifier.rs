@@ -0,0 +1,355 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use cuckoo::{verify_params, Params, Proof};
+use difficulty::Target;
+
+// Workers take this many proofs off the queue at a time
+const CLAIM_SIZE: usize = 4;
+
+// How long verify_one waits for other submissions to share its batch
+const GATHER_WINDOW_MS: u64 = 1;
+
+// One proof to check, with everything verify_params needs
+#[derive(Clone, Copy, Debug)]
+pub struct VerifyJob {
+    pub params: Params,
+    pub keys: [u64; 4],
+    pub proof: Proof,
+    pub easiness: i32,
+    pub target: Target,
+}
+
+impl VerifyJob {
+    pub fn verify(&self) -> bool {
+        verify_params(self.params, self.keys, self.proof, self.easiness, self.target)
+    }
+}
+
+// The proofs passed to one verify call, and how many are still unchecked
+struct Batch {
+    jobs: Vec<VerifyJob>,
+    valid: Vec<AtomicBool>,
+    left: Mutex<usize>,
+    done: Condvar,
+}
+
+// A run of proofs from one batch, as handed to a worker
+struct Claim {
+    batch: Arc<Batch>,
+    start: usize,
+    end: usize,
+}
+
+struct Queue {
+    // Claims not yet taken, and whether the workers should exit
+    pending: Mutex<(VecDeque<Claim>, bool)>,
+    ready: Condvar,
+}
+
+struct Pool {
+    queue: Arc<Queue>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl Pool {
+    fn start(threads: usize) -> Pool {
+        let queue = Arc::new(Queue {
+            pending: Mutex::new((VecDeque::new(), false)),
+            ready: Condvar::new(),
+        });
+        let workers = (0..threads)
+            .map(|_| {
+                let queue = queue.clone();
+                thread::spawn(move || work(&queue))
+            })
+            .collect();
+        Pool {
+            queue,
+            workers,
+        }
+    }
+}
+
+fn work(queue: &Queue) {
+    loop {
+        let claim = {
+            let mut pending = queue.pending.lock().unwrap();
+            loop {
+                if let Some(claim) = pending.0.pop_front() {
+                    break claim;
+                }
+                if pending.1 {
+                    return;
+                }
+                pending = queue.ready.wait(pending).unwrap();
+            }
+        };
+        let batch = &claim.batch;
+        for (job, valid) in batch.jobs[claim.start..claim.end].iter().zip(&batch.valid[claim.start..claim.end]) {
+            valid.store(job.verify(), Ordering::Relaxed);
+        }
+        let mut left = batch.left.lock().unwrap();
+        *left -= claim.end - claim.start;
+        if *left == 0 {
+            batch.done.notify_all();
+        }
+    }
+}
+
+// Single proofs collected by verify_one, and their results once checked
+struct Gathered {
+    jobs: Vec<VerifyJob>,
+    valid: Option<Vec<bool>>,
+}
+
+struct Gather {
+    state: Mutex<Gathered>,
+    done: Condvar,
+}
+
+// Checks proofs on a fixed set of worker threads, started on first use and
+// shared by every caller. However many threads submit proofs at once, no
+// more than `threads` proofs are being checked at any time.
+pub struct BatchVerifier {
+    threads: usize,
+    window: Duration,
+    pool: Mutex<Option<Pool>>,
+    // The batch verify_one callers are joining, until its window closes
+    gathering: Mutex<Option<Arc<Gather>>>,
+    // Calls to verify so far
+    batches: AtomicUsize,
+}
+
+impl BatchVerifier {
+    pub fn new() -> BatchVerifier {
+        BatchVerifier {
+            threads: thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            window: Duration::from_millis(GATHER_WINDOW_MS),
+            pool: Mutex::new(None),
+            gathering: Mutex::new(None),
+            batches: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn threads(mut self, threads: usize) -> BatchVerifier {
+        self.threads = if threads == 0 { 1 } else { threads };
+        self
+    }
+
+    // How long verify_one holds a batch open for other submissions. Zero
+    // checks every proof as its own batch.
+    pub fn window(mut self, window: Duration) -> BatchVerifier {
+        self.window = window;
+        self
+    }
+
+    // Whether one proof is valid. Proofs submitted from other threads
+    // within the window are checked together with it as one batch.
+    pub fn verify_one(&self, job: VerifyJob) -> bool {
+        if self.window == Duration::from_secs(0) {
+            return self.verify(&[job])[0];
+        }
+        let (gather, index) = {
+            let mut gathering = self.gathering.lock().unwrap();
+            if let Some(ref gather) = *gathering {
+                let mut state = gather.state.lock().unwrap();
+                state.jobs.push(job);
+                (gather.clone(), Some(state.jobs.len() - 1))
+            } else {
+                let gather = Arc::new(Gather {
+                    state: Mutex::new(Gathered {
+                        jobs: vec![job],
+                        valid: None,
+                    }),
+                    done: Condvar::new(),
+                });
+                *gathering = Some(gather.clone());
+                (gather, None)
+            }
+        };
+
+        match index {
+            // Another caller opened the batch and will check it
+            Some(index) => {
+                let mut state = gather.state.lock().unwrap();
+                loop {
+                    if let Some(ref valid) = state.valid {
+                        return valid[index];
+                    }
+                    state = gather.done.wait(state).unwrap();
+                }
+            }
+            None => {
+                thread::sleep(self.window);
+                // Jobs only join while the batch is open, so once it's
+                // closed they're all in
+                self.gathering.lock().unwrap().take();
+                let jobs = gather.state.lock().unwrap().jobs.clone();
+                let valid = self.verify(&jobs);
+                let first = valid[0];
+                gather.state.lock().unwrap().valid = Some(valid);
+                gather.done.notify_all();
+                first
+            }
+        }
+    }
+
+    // Whether each job's proof is valid, in the order given. Blocks until
+    // the workers have been through all of them.
+    pub fn verify(&self, jobs: &[VerifyJob]) -> Vec<bool> {
+        if jobs.is_empty() {
+            return Vec::new();
+        }
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        let batch = Arc::new(Batch {
+            jobs: jobs.to_vec(),
+            valid: jobs.iter().map(|_| AtomicBool::new(false)).collect(),
+            left: Mutex::new(jobs.len()),
+            done: Condvar::new(),
+        });
+
+        // Small claims, so a few slow proofs don't leave the other workers
+        // idle
+        {
+            let mut pool = self.pool.lock().unwrap();
+            let queue = &pool.get_or_insert_with(|| Pool::start(self.threads)).queue;
+            let mut pending = queue.pending.lock().unwrap();
+            for start in (0..jobs.len()).step_by(CLAIM_SIZE) {
+                pending.0.push_back(Claim {
+                    batch: batch.clone(),
+                    start,
+                    end: jobs.len().min(start + CLAIM_SIZE),
+                });
+            }
+            queue.ready.notify_all();
+        }
+
+        let mut left = batch.left.lock().unwrap();
+        while *left > 0 {
+            left = batch.done.wait(left).unwrap();
+        }
+        batch.valid.iter().map(|v| v.load(Ordering::Relaxed)).collect()
+    }
+}
+
+impl Default for BatchVerifier {
+    fn default() -> BatchVerifier {
+        BatchVerifier::new()
+    }
+}
+
+impl Drop for BatchVerifier {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.lock().unwrap().take() {
+            pool.queue.pending.lock().unwrap().1 = true;
+            pool.queue.ready.notify_all();
+            for worker in pool.workers {
+                worker.join().ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cuckoo::{Params, Variant};
+    use lean_miner;
+    use simple_miner::test_problem;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+    use verifier::{BatchVerifier, VerifyJob};
+
+    #[test]
+    fn batch_matches_single_verification() {
+        let mut jobs = Vec::new();
+        for &(variant, i) in [(Variant::Cuckoo, 0), (Variant::Cuckatoo, 33), (Variant::Cuckaroo, 7)].iter() {
+            let params = Params::new(variant, 16);
+            let cs = test_problem(params, &format!("lean {}", i));
+            let proof = lean_miner::solve(cs.clone()).unwrap();
+            let job = VerifyJob {
+                params,
+                keys: cs.graph_v,
+                proof,
+                easiness: cs.easiness,
+                target: cs.target,
+            };
+            jobs.push(job);
+
+            // A nonce that breaks the cycle, one out of order, and a graph
+            // the proof wasn't found in
+            let mut bad = job;
+            bad.proof[5] += 1;
+            jobs.push(bad);
+            let mut bad = job;
+            bad.proof.swap(0, 1);
+            jobs.push(bad);
+            let mut bad = job;
+            bad.keys[0] ^= 1;
+            jobs.push(bad);
+        }
+        let batch: Vec<VerifyJob> = jobs.iter().cycle().take(jobs.len() * 5).cloned().collect();
+
+        let want: Vec<bool> = batch.iter().map(|j| j.verify()).collect();
+        assert_eq!(want.iter().filter(|ok| **ok).count(), 15);
+        assert_eq!(BatchVerifier::new().threads(1).verify(&batch), want);
+        assert!(BatchVerifier::new().verify(&[]).is_empty());
+
+        // Callers on several threads share the same workers
+        let verifier = BatchVerifier::new().threads(3);
+        thread::scope(|scope| {
+            let callers: Vec<_> = (0..4)
+                .map(|i| {
+                    let (verifier, batch) = (&verifier, &batch[i..]);
+                    scope.spawn(move || verifier.verify(batch))
+                })
+                .collect();
+            for (i, caller) in callers.into_iter().enumerate() {
+                assert_eq!(caller.join().unwrap(), &want[i..]);
+            }
+        });
+        assert_eq!(verifier.verify(&batch[..1]), &want[..1]);
+    }
+
+    #[test]
+    fn single_proofs_share_batches() {
+        let params = Params::new(Variant::Cuckatoo, 16);
+        let cs = test_problem(params, "lean 33");
+        let job = VerifyJob {
+            params,
+            keys: cs.graph_v,
+            proof: lean_miner::solve(cs.clone()).unwrap(),
+            easiness: cs.easiness,
+            target: cs.target,
+        };
+        let mut bad = job;
+        bad.proof[5] += 1;
+        let jobs: Vec<VerifyJob> = (0..8).map(|i| if i % 2 == 0 { job } else { bad }).collect();
+
+        // A window long enough that every caller lands in it
+        let verifier = BatchVerifier::new().threads(2).window(Duration::from_millis(200));
+        thread::scope(|scope| {
+            let callers: Vec<_> = jobs
+                .iter()
+                .map(|job| {
+                    let verifier = &verifier;
+                    scope.spawn(move || verifier.verify_one(*job))
+                })
+                .collect();
+            for (i, caller) in callers.into_iter().enumerate() {
+                assert_eq!(caller.join().unwrap(), i % 2 == 0);
+            }
+        });
+        assert!(verifier.batches.load(Ordering::Relaxed) < jobs.len());
+
+        let verifier = BatchVerifier::new().window(Duration::from_secs(0));
+        assert!(verifier.verify_one(job));
+        assert!(!verifier.verify_one(bad));
+    }
+}